@@ -9,7 +9,7 @@ use rusttype::gpu_cache::Cache;
 use rusttype::{point, vector, Font, PositionedGlyph, Rect, Scale};
 
 mod graphics;
-mod parser;
+use ldraw::parser;
 
 fn layout_paragraph<'a>(
     font: &Font<'a>,
@@ -284,10 +284,21 @@ fn main() {
     let mut y_max = f32::MIN;
     let mut z_max = f32::MIN;
     let start = Instant::now();
-    let polygons = parser::load("/home/paul/Downloads/ldraw/", "car.ldr");
+    let mut parser = parser::Parser::new("/home/paul/Downloads/ldraw/").unwrap_or_else(|e| {
+        eprintln!("ERROR: couldn't open the library: {}", e);
+        std::process::exit(1);
+    });
+    let geometry = parser.load("car.ldr").unwrap_or_else(|e| {
+        eprintln!("ERROR: {}", e);
+        Default::default()
+    });
+    for diagnostic in parser.take_diagnostics() {
+        println!("WARNING: {}. Skipping...", diagnostic);
+    }
+    let polygons = &geometry.polygons;
     let middle = Instant::now();
     let mut vertices = Vec::new();
-    for polygon in &polygons {
+    for polygon in polygons {
         let color = match polygon.color {
             parser::LdrawColor::RGBA(r, g, b, a) => [r, g, b, a],
             _ => [0.0, 1.0, 0.0, 1.0],
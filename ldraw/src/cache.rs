@@ -0,0 +1,608 @@
+use cgmath::{Matrix4, Point3};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use super::library::LibraryConfig;
+use super::parser::{ConditionalEdge, Edge, FileHeader, Geometry, LdrawColor, PartReference, Polygon, Step};
+
+/// Bump this whenever parsing changes what ends up in a `Geometry`, so that
+/// entries written by older versions are ignored.
+pub const PARSER_VERSION: u32 = 4;
+
+const MAGIC: &[u8; 4] = b"LDGC";
+
+/// The size and modification time of a file an entry was built from.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Stamp {
+    pub size: u64,
+    pub seconds: u64,
+    pub nanos: u32,
+}
+
+/// The stamp of a file on disk. Files inside an archive are stamped with
+/// the archive itself. Library files should be stamped through their
+/// source, as files that aren't on disk have no stamp and anything built
+/// from them isn't cached.
+pub fn stamp(path: &Path) -> Option<Stamp> {
+    let metadata = path.ancestors().find_map(|path| fs::metadata(path).ok().filter(|m| m.is_file()))?;
+    let modified = metadata.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+    Some(Stamp {
+        size: metadata.len(),
+        seconds: modified.as_secs(),
+        nanos: modified.subsec_nanos(),
+    })
+}
+
+/// A file an entry was built from, with how it was found: the name it was
+/// referenced by and the file that referenced it. An entry is only valid
+/// while each of these is found at the same path with the same stamp, so
+/// adding a file that shadows one of them invalidates it too.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Dependency {
+    pub filename: String,
+    pub referenced_from: Option<PathBuf>,
+    pub path: PathBuf,
+    pub stamp: Stamp,
+}
+
+impl Dependency {
+    /// Looks a file up in `library`, if it's found in a source on disk.
+    pub fn find(library: &LibraryConfig, filename: &str, referenced_from: Option<&Path>) -> Option<Self> {
+        let (path, stamp) = library.stamp(filename, referenced_from)?;
+        Some(Self {
+            filename: filename.into(),
+            referenced_from: referenced_from.map(PathBuf::from),
+            path,
+            stamp,
+        })
+    }
+}
+
+/// FNV-1a, which unlike `DefaultHasher` is the same in every build, so
+/// anything named by it keeps its name across versions of the compiler.
+pub fn fnv1a<I: IntoIterator<Item = u8>>(bytes: I) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in bytes {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash
+}
+
+fn hash(key: &str, inverted: bool) -> u64 {
+    fnv1a(key.bytes().chain(std::iter::once(inverted as u8)))
+}
+
+/// Fully resolved geometry saved between runs. Entries are keyed by the
+/// resolved path of a file and whether it's inverted, and are only used
+/// while the parser version, the context they were built in and every file
+/// they were built from are unchanged.
+///
+/// The context is anything else the geometry depends on, which for `Parser`
+/// is its color table and the library sources it searched.
+#[derive(Clone, Debug)]
+pub struct DiskCache {
+    directory: PathBuf,
+}
+
+impl DiskCache {
+    pub fn new<P: Into<PathBuf>>(directory: P) -> Self {
+        Self { directory: directory.into() }
+    }
+
+    pub fn directory(&self) -> &Path {
+        &self.directory
+    }
+
+    fn entry_path(&self, key: &str, inverted: bool) -> PathBuf {
+        self.directory.join(format!("{:016x}.bin", hash(key, inverted)))
+    }
+
+    /// The geometry and header stored for a file, if there's an entry that's
+    /// still valid in `context` with the files in `library`.
+    pub fn get(&self, key: &str, inverted: bool, context: u64, library: &LibraryConfig) -> Option<(Geometry, FileHeader)> {
+        let data = fs::read(self.entry_path(key, inverted)).ok()?;
+        let mut reader = Reader { data: &data, position: 0 };
+        if reader.bytes(4)? != MAGIC
+            || reader.u32()? != PARSER_VERSION
+            || reader.string()? != env!("CARGO_PKG_VERSION")
+            || reader.string()? != key
+            || reader.u8()? != inverted as u8
+            || reader.u64()? != context
+        {
+            return None;
+        }
+        for _ in 0..reader.count()? {
+            let stored = Dependency {
+                filename: reader.string()?,
+                referenced_from: reader.optional_string()?.map(PathBuf::from),
+                path: PathBuf::from(reader.string()?),
+                stamp: Stamp {
+                    size: reader.u64()?,
+                    seconds: reader.u64()?,
+                    nanos: reader.u32()?,
+                },
+            };
+            if Dependency::find(library, &stored.filename, stored.referenced_from.as_deref()) != Some(stored) {
+                return None;
+            }
+        }
+        let header = reader.header()?;
+        let geometry = reader.geometry()?;
+        Some((geometry, header))
+    }
+
+    /// Stores a file's geometry, built in `context` from `dependencies` (the
+    /// file itself and every file under it).
+    pub fn put(
+        &self,
+        key: &str,
+        inverted: bool,
+        context: u64,
+        geometry: &Geometry,
+        header: &FileHeader,
+        dependencies: &[Dependency],
+    ) -> io::Result<()> {
+        let mut writer = Writer::default();
+        writer.data.extend_from_slice(MAGIC);
+        writer.u32(PARSER_VERSION);
+        writer.string(env!("CARGO_PKG_VERSION"));
+        writer.string(key);
+        writer.u8(inverted as u8);
+        writer.u64(context);
+        writer.u32(dependencies.len() as u32);
+        for dependency in dependencies {
+            writer.string(&dependency.filename);
+            writer.optional_string(&dependency.referenced_from.as_ref().map(|path| path.to_string_lossy().into()));
+            writer.string(&dependency.path.to_string_lossy());
+            writer.u64(dependency.stamp.size);
+            writer.u64(dependency.stamp.seconds);
+            writer.u32(dependency.stamp.nanos);
+        }
+        writer.header(header);
+        writer.geometry(geometry);
+
+        // Write to a temporary file first so that a reader never sees half
+        // an entry.
+        fs::create_dir_all(&self.directory)?;
+        let path = self.entry_path(key, inverted);
+        let temporary = path.with_extension(format!("tmp{}", std::process::id()));
+        fs::write(&temporary, &writer.data)?;
+        fs::rename(&temporary, &path)
+    }
+
+    /// Removes every entry.
+    pub fn clear(&self) -> io::Result<()> {
+        match fs::remove_dir_all(&self.directory) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Writer {
+    data: Vec<u8>,
+}
+
+impl Writer {
+    fn u8(&mut self, value: u8) {
+        self.data.push(value);
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn u64(&mut self, value: u64) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn f32(&mut self, value: f32) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn string(&mut self, value: &str) {
+        self.u32(value.len() as u32);
+        self.data.extend_from_slice(value.as_bytes());
+    }
+
+    fn optional_string(&mut self, value: &Option<String>) {
+        match value {
+            Some(value) => {
+                self.u8(1);
+                self.string(value);
+            }
+            None => self.u8(0),
+        }
+    }
+
+    fn strings(&mut self, values: &[String]) {
+        self.u32(values.len() as u32);
+        for value in values {
+            self.string(value);
+        }
+    }
+
+    fn point(&mut self, point: &Point3<f32>) {
+        self.f32(point.x);
+        self.f32(point.y);
+        self.f32(point.z);
+    }
+
+    fn color(&mut self, color: &LdrawColor) {
+        match *color {
+            LdrawColor::Main => self.u8(0),
+            LdrawColor::Complement => self.u8(1),
+            LdrawColor::RGBA(r, g, b, a) => {
+                self.u8(2);
+                for value in &[r, g, b, a] {
+                    self.f32(*value);
+                }
+            }
+        }
+    }
+
+    fn header(&mut self, header: &FileHeader) {
+        self.string(&header.title);
+        self.optional_string(&header.name);
+        self.optional_string(&header.author);
+        self.optional_string(&header.ldraw_org);
+        self.optional_string(&header.license);
+        self.optional_string(&header.category);
+        self.strings(&header.keywords);
+        self.strings(&header.history);
+        self.strings(&header.help);
+    }
+
+    fn geometry(&mut self, geometry: &Geometry) {
+        self.u32(geometry.polygons.len() as u32);
+        for polygon in &geometry.polygons {
+            self.u32(polygon.points.len() as u32);
+            for point in &polygon.points {
+                self.point(point);
+            }
+            self.color(&polygon.color);
+            self.u8(polygon.cull as u8);
+        }
+        self.u32(geometry.edges.len() as u32);
+        for edge in &geometry.edges {
+            self.point(&edge.points[0]);
+            self.point(&edge.points[1]);
+            self.color(&edge.color);
+        }
+        self.u32(geometry.conditional_edges.len() as u32);
+        for edge in &geometry.conditional_edges {
+            self.point(&edge.points[0]);
+            self.point(&edge.points[1]);
+            self.point(&edge.controls[0]);
+            self.point(&edge.controls[1]);
+            self.color(&edge.color);
+        }
+        self.u32(geometry.steps.len() as u32);
+        for step in &geometry.steps {
+            self.u64(step.polygons as u64);
+            self.u64(step.edges as u64);
+            self.u64(step.conditional_edges as u64);
+            self.u32(step.parts.len() as u32);
+            for part in &step.parts {
+                self.string(&part.name);
+                self.color(&part.color);
+                self.color(&part.edge_color);
+                let m: &[f32; 16] = part.transform.as_ref();
+                for value in m {
+                    self.f32(*value);
+                }
+                self.u8(part.inverted as u8);
+                self.u8(part.clip as u8);
+            }
+        }
+    }
+}
+
+/// Reads what `Writer` wrote. Every method gives `None` if the data ends
+/// early or is malformed.
+struct Reader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, length: usize) -> Option<&'a [u8]> {
+        let bytes = self.data.get(self.position..self.position.checked_add(length)?)?;
+        self.position += length;
+        Some(bytes)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        Some(self.bytes(1)?[0])
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(self.bytes(4)?);
+        Some(u32::from_le_bytes(bytes))
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.bytes(8)?);
+        Some(u64::from_le_bytes(bytes))
+    }
+
+    fn f32(&mut self) -> Option<f32> {
+        Some(f32::from_bits(self.u32()?))
+    }
+
+    fn bool(&mut self) -> Option<bool> {
+        Some(self.u8()? != 0)
+    }
+
+    /// A count of items, which can't be more than the bytes that are left,
+    /// so that a corrupt entry can't cause a huge allocation.
+    fn count(&mut self) -> Option<usize> {
+        let count = self.u32()? as usize;
+        if count > self.data.len() - self.position {
+            return None;
+        }
+        Some(count)
+    }
+
+    fn string(&mut self) -> Option<String> {
+        let length = self.count()?;
+        String::from_utf8(self.bytes(length)?.to_vec()).ok()
+    }
+
+    fn optional_string(&mut self) -> Option<Option<String>> {
+        match self.u8()? {
+            0 => Some(None),
+            _ => Some(Some(self.string()?)),
+        }
+    }
+
+    fn strings(&mut self) -> Option<Vec<String>> {
+        (0..self.count()?).map(|_| self.string()).collect()
+    }
+
+    fn point(&mut self) -> Option<Point3<f32>> {
+        Some(Point3::new(self.f32()?, self.f32()?, self.f32()?))
+    }
+
+    fn color(&mut self) -> Option<LdrawColor> {
+        match self.u8()? {
+            0 => Some(LdrawColor::Main),
+            1 => Some(LdrawColor::Complement),
+            2 => Some(LdrawColor::RGBA(self.f32()?, self.f32()?, self.f32()?, self.f32()?)),
+            _ => None,
+        }
+    }
+
+    fn header(&mut self) -> Option<FileHeader> {
+        Some(FileHeader {
+            title: self.string()?,
+            name: self.optional_string()?,
+            author: self.optional_string()?,
+            ldraw_org: self.optional_string()?,
+            license: self.optional_string()?,
+            category: self.optional_string()?,
+            keywords: self.strings()?,
+            history: self.strings()?,
+            help: self.strings()?,
+        })
+    }
+
+    fn geometry(&mut self) -> Option<Geometry> {
+        let mut geometry = Geometry::default();
+        for _ in 0..self.count()? {
+            let points = (0..self.count()?).map(|_| self.point()).collect::<Option<_>>()?;
+            geometry.polygons.push(Polygon {
+                points,
+                color: self.color()?,
+                cull: self.bool()?,
+            });
+        }
+        for _ in 0..self.count()? {
+            geometry.edges.push(Edge {
+                points: [self.point()?, self.point()?],
+                color: self.color()?,
+            });
+        }
+        for _ in 0..self.count()? {
+            geometry.conditional_edges.push(ConditionalEdge {
+                points: [self.point()?, self.point()?],
+                controls: [self.point()?, self.point()?],
+                color: self.color()?,
+            });
+        }
+        for _ in 0..self.count()? {
+            let mut step = Step {
+                polygons: self.u64()? as usize,
+                edges: self.u64()? as usize,
+                conditional_edges: self.u64()? as usize,
+                parts: Vec::new(),
+            };
+            for _ in 0..self.count()? {
+                let name = self.string()?;
+                let color = self.color()?;
+                let edge_color = self.color()?;
+                let mut m = [0.0; 16];
+                for value in &mut m {
+                    *value = self.f32()?;
+                }
+                #[rustfmt::skip]
+                let transform = Matrix4::new(
+                    m[0], m[1], m[2], m[3],
+                    m[4], m[5], m[6], m[7],
+                    m[8], m[9], m[10], m[11],
+                    m[12], m[13], m[14], m[15],
+                );
+                step.parts.push(PartReference {
+                    name,
+                    color,
+                    edge_color,
+                    transform,
+                    inverted: self.bool()?,
+                    clip: self.bool()?,
+                });
+            }
+            if step.polygons > geometry.polygons.len()
+                || step.edges > geometry.edges.len()
+                || step.conditional_edges > geometry.conditional_edges.len()
+            {
+                return None;
+            }
+            geometry.steps.push(step);
+        }
+        Some(geometry)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::library::MemorySource;
+    use std::time::Duration;
+
+    /// An empty folder for one test's files.
+    fn temp_dir(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("ldraw-cache-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        path
+    }
+
+    fn geometry() -> Geometry {
+        let mut geometry = Geometry::default();
+        geometry.polygons.push(Polygon {
+            points: vec![Point3::new(0.0, 0.0, 0.0), Point3::new(1.0, 0.0, 0.0), Point3::new(0.0, 1.0, 0.0)],
+            color: LdrawColor::RGBA(1.0, 0.0, 0.0, 1.0),
+            cull: true,
+        });
+        geometry.edges.push(Edge {
+            points: [Point3::new(0.0, 0.0, 0.0), Point3::new(1.0, 0.0, 0.0)],
+            color: LdrawColor::Complement,
+        });
+        geometry.steps.push(Step {
+            polygons: 1,
+            edges: 1,
+            conditional_edges: 0,
+            parts: vec![PartReference {
+                name: "s/a.dat".into(),
+                color: LdrawColor::Main,
+                edge_color: LdrawColor::Complement,
+                transform: Matrix4::from_translation([0.0, -4.0, 0.0].into()),
+                inverted: true,
+                clip: false,
+            }],
+        });
+        geometry
+    }
+
+    /// A library in `root` with a part in `parts`, and a cache entry for it.
+    fn cached_part(root: &Path) -> (LibraryConfig, DiskCache) {
+        fs::create_dir_all(root.join("library/parts")).unwrap();
+        fs::write(root.join("library/parts/a.dat"), "0 Part\n").unwrap();
+        let library = LibraryConfig::new(root.join("library")).unwrap();
+        let dependency = Dependency::find(&library, "a.dat", None).unwrap();
+        let disk_cache = DiskCache::new(root.join("cache"));
+        let header = FileHeader {
+            title: "Part".into(),
+            ..FileHeader::default()
+        };
+        disk_cache.put(&dependency.path.display().to_string(), false, 7, &geometry(), &header, &[dependency]).unwrap();
+        (library, disk_cache)
+    }
+
+    fn get(library: &LibraryConfig, disk_cache: &DiskCache, context: u64) -> Option<(Geometry, FileHeader)> {
+        let key = library.find("a.dat", None).unwrap().display().to_string();
+        disk_cache.get(&key, false, context, library)
+    }
+
+    #[test]
+    fn entries_round_trip() {
+        let root = temp_dir("round-trip");
+        let (library, disk_cache) = cached_part(&root);
+        let (geometry, header) = get(&library, &disk_cache, 7).unwrap();
+        assert_eq!(format!("{:?}", geometry), format!("{:?}", self::geometry()));
+        assert_eq!(header.title, "Part");
+
+        assert!(get(&library, &disk_cache, 8).is_none());
+        let key = library.find("a.dat", None).unwrap().display().to_string();
+        assert!(disk_cache.get(&key, true, 7, &library).is_none());
+    }
+
+    #[test]
+    fn entries_are_invalid_once_a_file_changes() {
+        let root = temp_dir("changes");
+        let path = root.join("library/parts/a.dat");
+
+        let (library, disk_cache) = cached_part(&root);
+        fs::write(&path, "0 Another part\n").unwrap();
+        assert!(get(&library, &disk_cache, 7).is_none());
+
+        let (library, disk_cache) = cached_part(&root);
+        let modified = fs::metadata(&path).unwrap().modified().unwrap();
+        let file = fs::File::options().write(true).open(&path).unwrap();
+        file.set_modified(modified - Duration::from_secs(60)).unwrap();
+        assert!(get(&library, &disk_cache, 7).is_none());
+    }
+
+    #[test]
+    fn entries_are_invalid_once_a_file_shadows_a_dependency() {
+        let root = temp_dir("shadow");
+        fs::create_dir_all(root.join("library/unofficial")).unwrap();
+        let (_, disk_cache) = cached_part(&root);
+        let library = LibraryConfig::new(root.join("library")).unwrap();
+        assert!(get(&library, &disk_cache, 7).is_some());
+
+        // The unofficial folder comes first.
+        let mut library = LibraryConfig::empty();
+        library.add_root(root.join("library/unofficial"));
+        library.add_root(root.join("library"));
+        assert!(get(&library, &disk_cache, 7).is_some());
+        fs::create_dir_all(root.join("library/unofficial/parts")).unwrap();
+        fs::write(root.join("library/unofficial/parts/a.dat"), "0 Part\n").unwrap();
+        assert!(get(&library, &disk_cache, 7).is_none());
+    }
+
+    #[test]
+    fn entries_from_other_versions_or_damaged_files_are_ignored() {
+        let root = temp_dir("damaged");
+        let (library, disk_cache) = cached_part(&root);
+        let key = library.find("a.dat", None).unwrap().display().to_string();
+        let entry = disk_cache.entry_path(&key, false);
+        let data = fs::read(&entry).unwrap();
+
+        let mut older = data.clone();
+        older[4..8].copy_from_slice(&(PARSER_VERSION - 1).to_le_bytes());
+        fs::write(&entry, older).unwrap();
+        assert!(get(&library, &disk_cache, 7).is_none());
+
+        for length in [0, 4, data.len() / 2, data.len() - 1] {
+            fs::write(&entry, &data[..length]).unwrap();
+            assert!(get(&library, &disk_cache, 7).is_none());
+        }
+
+        let mut garbled = data.clone();
+        for byte in &mut garbled[40..] {
+            *byte = 0xff;
+        }
+        fs::write(&entry, garbled).unwrap();
+        assert!(get(&library, &disk_cache, 7).is_none());
+
+        fs::write(&entry, &data).unwrap();
+        assert!(get(&library, &disk_cache, 7).is_some());
+    }
+
+    #[test]
+    fn files_that_are_not_on_disk_are_not_stamped() {
+        let mut source = MemorySource::new();
+        source.insert("Cargo.toml", "");
+        let mut library = LibraryConfig::empty();
+        library.add_source(source);
+        assert!(library.find("Cargo.toml", None).is_some());
+        assert!(Dependency::find(&library, "Cargo.toml", None).is_none());
+    }
+}
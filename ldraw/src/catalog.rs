@@ -0,0 +1,436 @@
+use super::cache::fnv1a;
+use super::document;
+use super::library::LibraryConfig;
+use super::parser::FileHeader;
+use rayon::prelude::*;
+use std::cmp::Ordering;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+const MAGIC: &str = "ldraw catalog 2";
+
+/// A part that can be picked from the library.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CatalogEntry {
+    /// The file name it's referenced by, lowercased, e.g. "3005.dat".
+    pub name: String,
+    pub title: String,
+    /// `!CATEGORY`, or the first word of the title if there isn't one.
+    pub category: String,
+    pub keywords: Vec<String>,
+    /// The file type from `!LDRAW_ORG`, e.g. "Part" or "Unofficial_Part",
+    /// or nothing for files without one.
+    pub file_type: String,
+}
+
+impl CatalogEntry {
+    fn new(name: String, header: FileHeader) -> Self {
+        let category = header.category.clone().unwrap_or_else(|| {
+            let title = header.title.trim_start_matches(['=', '_', '|']);
+            title.split_whitespace().next().unwrap_or_default().into()
+        });
+        Self {
+            name,
+            category,
+            file_type: header.file_type().unwrap_or_default().into(),
+            title: header.title,
+            keywords: header.keywords,
+        }
+    }
+
+    /// The part number, which is the name without its extension, e.g.
+    /// "3005".
+    pub fn number(&self) -> &str {
+        self.name.rsplit_once('.').map_or(&self.name, |(number, _)| number)
+    }
+
+    fn matches(&self, term: &Term) -> bool {
+        match term {
+            Term::Category(category) => self.category.to_lowercase() == *category,
+            Term::Keyword(keyword) => self.keywords.iter().any(|k| k.to_lowercase() == *keyword),
+            Term::Word(word) => {
+                self.number().starts_with(word.as_str())
+                    || self
+                        .title
+                        .to_lowercase()
+                        .split(|c: char| !c.is_alphanumeric())
+                        .any(|w| w.starts_with(word.as_str()))
+                    || self.keywords.iter().any(|k| k.to_lowercase().contains(word.as_str()))
+            }
+        }
+    }
+}
+
+/// Orders part numbers by their numbers rather than as text, so that 3005
+/// comes before 30000.
+fn compare_numbers(a: &str, b: &str) -> Ordering {
+    let split = |s: &str| {
+        let digits = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+        (s[..digits].parse::<u64>().unwrap_or(u64::MAX), s.to_string())
+    };
+    split(a).cmp(&split(b))
+}
+
+#[derive(Debug, PartialEq)]
+enum Term {
+    Category(String),
+    Keyword(String),
+    Word(String),
+}
+
+/// The terms of a search, which are words, `category:name` or
+/// `keyword:name`. Values with spaces can be quoted, e.g.
+/// `keyword:"technic pin"`.
+fn parse_query(query: &str) -> Vec<Term> {
+    let mut tokens = Vec::new();
+    let mut token = String::new();
+    let mut quoted = false;
+    for c in query.to_lowercase().chars() {
+        match c {
+            '"' => quoted = !quoted,
+            c if c.is_whitespace() && !quoted => tokens.extend(Some(std::mem::take(&mut token)).filter(|t| !t.is_empty())),
+            c => token.push(c),
+        }
+    }
+    tokens.extend(Some(token).filter(|t| !t.is_empty()));
+    tokens
+        .into_iter()
+        .map(|token| match token.split_once(':') {
+            Some(("category", value)) | Some(("cat", value)) => Term::Category(value.into()),
+            Some(("keyword", value)) | Some(("key", value)) => Term::Keyword(value.into()),
+            _ => Term::Word(token),
+        })
+        .collect()
+}
+
+/// Escapes a field for `save`: backslashes, tabs and line breaks, and
+/// `separator` if there is one.
+fn escape(field: &str, separator: Option<char>) -> String {
+    let mut escaped = String::with_capacity(field.len());
+    for c in field.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '\t' => escaped.push_str("\\t"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            c if Some(c) == separator => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Splits a field written by `escape` at each `separator` that isn't
+/// escaped, and undoes the escapes.
+fn unescape(field: &str, separator: Option<char>) -> Vec<String> {
+    let mut values = vec![String::new()];
+    let mut chars = field.chars();
+    while let Some(c) = chars.next() {
+        let value = values.last_mut().unwrap();
+        match c {
+            '\\' => match chars.next() {
+                Some('t') => value.push('\t'),
+                Some('n') => value.push('\n'),
+                Some('r') => value.push('\r'),
+                Some(c) => value.push(c),
+                None => value.push('\\'),
+            },
+            c if Some(c) == separator => values.push(String::new()),
+            c => value.push(c),
+        }
+    }
+    values
+}
+
+/// Every part in the library, with what's needed to search for them. The
+/// catalog is saved so that the library only has to be read when it
+/// changes.
+#[derive(Clone, Debug, Default)]
+pub struct Catalog {
+    entries: Vec<CatalogEntry>,
+    /// A hash of the files in the library when the catalog was built.
+    fingerprint: u64,
+}
+
+impl Catalog {
+    /// The `parts` files of every library source, after those an earlier
+    /// source overrides are left out, and a hash of their paths, sizes and
+    /// modification times.
+    fn list(library: &LibraryConfig) -> (Vec<(usize, PathBuf)>, u64) {
+        let mut files = Vec::new();
+        let mut names = std::collections::HashSet::new();
+        for (i, source) in library.sources.iter().enumerate() {
+            let mut paths = source.list(Path::new("parts"));
+            paths.sort();
+            for path in paths {
+                let name = path.file_name().unwrap_or_default().to_string_lossy().to_lowercase();
+                if name.ends_with(".dat") && names.insert(name) {
+                    files.push((i, path));
+                }
+            }
+        }
+        let mut listing = Vec::new();
+        for (source, path) in &files {
+            listing.extend(path.to_string_lossy().bytes());
+            if let Some(stamp) = library.sources[*source].stamp(path) {
+                listing.extend_from_slice(&stamp.size.to_le_bytes());
+                listing.extend_from_slice(&stamp.seconds.to_le_bytes());
+                listing.extend_from_slice(&stamp.nanos.to_le_bytes());
+            }
+            listing.push(b'\n');
+        }
+        (files, fnv1a(listing))
+    }
+
+    /// Reads the header of every part in the library. Parts whose titles
+    /// start with `~`, such as moved parts and ones only meant to be used
+    /// inside others, are left out.
+    pub fn build(library: &LibraryConfig) -> Self {
+        let (files, fingerprint) = Self::list(library);
+        Self::build_from(library, files, fingerprint)
+    }
+
+    fn build_from(library: &LibraryConfig, files: Vec<(usize, PathBuf)>, fingerprint: u64) -> Self {
+        let mut entries: Vec<CatalogEntry> = files
+            .par_iter()
+            .filter_map(|(source, path)| {
+                let data = library.sources[*source].read(path).ok()?;
+                let header = FileHeader::parse(&document::decode_normalized(&data).0);
+                if header.title.starts_with('~') {
+                    return None;
+                }
+                let name = path.file_name()?.to_string_lossy().to_lowercase();
+                Some(CatalogEntry::new(name, header))
+            })
+            .collect();
+        entries.sort_by(|a, b| compare_numbers(&a.name, &b.name));
+        Self { entries, fingerprint }
+    }
+
+    /// The catalog saved at `path` if the library's files haven't changed
+    /// since, or else a new one, which is saved there. The catalog is
+    /// returned along with whether saving it worked, as it's still usable
+    /// if it couldn't be saved.
+    pub fn open<P: AsRef<Path>>(library: &LibraryConfig, path: P) -> (Self, io::Result<()>) {
+        let path = path.as_ref();
+        let (files, fingerprint) = Self::list(library);
+        match Self::load(path) {
+            Ok(catalog) if catalog.fingerprint == fingerprint => return (catalog, Ok(())),
+            _ => {}
+        }
+        let catalog = Self::build_from(library, files, fingerprint);
+        let saved = catalog.save(path);
+        (catalog, saved)
+    }
+
+    /// Reads a catalog written by `save`.
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, "not a part catalog");
+        let text = fs::read_to_string(path)?;
+        let mut lines = text.lines();
+        let fingerprint = match lines.next().and_then(|line| line.split_once('\t')) {
+            Some((MAGIC, fingerprint)) => u64::from_str_radix(fingerprint, 16).map_err(|_| invalid())?,
+            _ => return Err(invalid()),
+        };
+        let mut entries = Vec::new();
+        for line in lines {
+            let fields: Vec<&str> = line.splitn(5, '\t').collect();
+            if let [name, file_type, category, keywords, title] = fields[..] {
+                let field = |field| unescape(field, None).remove(0);
+                entries.push(CatalogEntry {
+                    name: field(name),
+                    title: field(title),
+                    category: field(category),
+                    keywords: unescape(keywords, Some(',')).into_iter().filter(|k| !k.is_empty()).collect(),
+                    file_type: field(file_type),
+                });
+            } else {
+                return Err(invalid());
+            }
+        }
+        Ok(Self { entries, fingerprint })
+    }
+
+    /// Writes the catalog as a line per part with tab-separated fields, and
+    /// the keywords separated by commas. Tabs, line breaks and commas in
+    /// keywords are escaped with backslashes.
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let field = |field: &str| escape(field, None);
+        let mut text = format!("{}\t{:x}\n", MAGIC, self.fingerprint);
+        for entry in &self.entries {
+            let keywords: Vec<String> = entry.keywords.iter().map(|k| escape(k, Some(','))).collect();
+            text.push_str(&format!(
+                "{}\t{}\t{}\t{}\t{}\n",
+                field(&entry.name),
+                field(&entry.file_type),
+                field(&entry.category),
+                keywords.join(","),
+                field(&entry.title)
+            ));
+        }
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(path, text)
+    }
+
+    /// Every part, in order of part number.
+    pub fn entries(&self) -> &[CatalogEntry] {
+        &self.entries
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// The part with the file name `name`, e.g. "3005.dat".
+    pub fn get(&self, name: &str) -> Option<&CatalogEntry> {
+        let name = name.to_lowercase();
+        self.entries.iter().find(|entry| entry.name == name)
+    }
+
+    /// Every category, in alphabetical order.
+    pub fn categories(&self) -> Vec<&str> {
+        let mut categories: Vec<&str> = self.entries.iter().map(|entry| entry.category.as_str()).collect();
+        categories.sort_unstable();
+        categories.dedup();
+        categories
+    }
+
+    /// The parts that match every term of `query`. Words match the start of
+    /// a part number or of a word in the title, or part of a keyword, and
+    /// `category:name` and `keyword:name` match those exactly, ignoring
+    /// case. Parts numbered exactly as a word come first, then ones whose
+    /// number starts with one, then the rest, each in order of part number.
+    pub fn search(&self, query: &str) -> Vec<&CatalogEntry> {
+        let terms = parse_query(query);
+        let mut results: Vec<(usize, &CatalogEntry)> = self
+            .entries
+            .iter()
+            .filter(|entry| terms.iter().all(|term| entry.matches(term)))
+            .map(|entry| {
+                let words = terms.iter().filter_map(|term| match term {
+                    Term::Word(word) => Some(word.as_str()),
+                    _ => None,
+                });
+                let rank = words
+                    .map(|word| match entry.number() {
+                        number if number == word => 0,
+                        number if number.starts_with(word) => 1,
+                        _ => 2,
+                    })
+                    .min()
+                    .unwrap_or(2);
+                (rank, entry)
+            })
+            .collect();
+        // The sort is stable, so each rank stays in order of part number.
+        results.sort_by_key(|(rank, _)| *rank);
+        results.into_iter().map(|(_, entry)| entry).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(name: &str, title: &str, category: &str, keywords: &[&str]) -> CatalogEntry {
+        CatalogEntry {
+            name: name.into(),
+            title: title.into(),
+            category: category.into(),
+            keywords: keywords.iter().map(|k| k.to_string()).collect(),
+            file_type: "Part".into(),
+        }
+    }
+
+    fn catalog() -> Catalog {
+        let mut entries = vec![
+            entry("30000.dat", "Brick 1 x 1 with Stud", "Brick", &[]),
+            entry("3005.dat", "Brick 1 x 1", "Brick", &["Technic Pin", "round"]),
+            entry("300.dat", "Plate 3 x 3", "Plate", &[]),
+            entry("3001.dat", "Brick 2 x 4", "Brick", &["Basic"]),
+            entry("3001p01.dat", "Brick 2 x 4 with Pattern", "Brick", &[]),
+            entry("4300.dat", "Brick 2 x 2 with 3005 Stamp", "Brick", &["3005"]),
+            entry("u9001.dat", "Sticker Technic", "Sticker", &[]),
+        ];
+        entries.sort_by(|a, b| compare_numbers(&a.name, &b.name));
+        Catalog { entries, fingerprint: 0x1234 }
+    }
+
+    fn names(entries: Vec<&CatalogEntry>) -> Vec<&str> {
+        entries.into_iter().map(|entry| entry.name.as_str()).collect()
+    }
+
+    #[test]
+    fn queries() {
+        assert_eq!(
+            parse_query("Brick  category:Plate keyword:\"Technic Pin\" \"2 x 4\" cat:x key:y"),
+            [
+                Term::Word("brick".into()),
+                Term::Category("plate".into()),
+                Term::Keyword("technic pin".into()),
+                Term::Word("2 x 4".into()),
+                Term::Category("x".into()),
+                Term::Keyword("y".into()),
+            ]
+        );
+        assert_eq!(parse_query("  "), []);
+        assert_eq!(parse_query("colour:red"), [Term::Word("colour:red".into())]);
+    }
+
+    #[test]
+    fn numbers_sort_by_value() {
+        let mut numbers = vec!["30000", "3005", "u9001", "300", "3001p01", "3001"];
+        numbers.sort_by(|a, b| compare_numbers(a, b));
+        assert_eq!(numbers, ["300", "3001", "3001p01", "3005", "30000", "u9001"]);
+    }
+
+    #[test]
+    fn search_ranks_exact_numbers_then_prefixes() {
+        let catalog = catalog();
+        // 4300 matches through its keyword.
+        assert_eq!(
+            names(catalog.search("300")),
+            ["300.dat", "3001.dat", "3001p01.dat", "3005.dat", "30000.dat", "4300.dat"]
+        );
+        // 4300 only matches through its title and keywords.
+        assert_eq!(names(catalog.search("3005")), ["3005.dat", "4300.dat"]);
+        assert_eq!(names(catalog.search("brick 3001")), ["3001.dat", "3001p01.dat"]);
+        assert_eq!(names(catalog.search("category:PLATE")), ["300.dat"]);
+        assert_eq!(names(catalog.search("keyword:\"technic pin\"")), ["3005.dat"]);
+        assert_eq!(names(catalog.search("technic")), ["3005.dat", "u9001.dat"]);
+        assert_eq!(catalog.search("").len(), catalog.len());
+        assert!(catalog.search("brick category:plate").is_empty());
+    }
+
+    #[test]
+    fn saved_catalogs_load_the_same() {
+        let mut catalog = catalog();
+        catalog.entries.push(entry(
+            "odd.dat",
+            "Title with\ttab, newline\n and \\t backslash",
+            "Odd\tCategory",
+            &["comma, inside", "back\\slash,", "tab\tkeyword"],
+        ));
+        let path = std::env::temp_dir().join(format!("ldraw-catalog-{}.txt", std::process::id()));
+        catalog.save(&path).unwrap();
+        let loaded = Catalog::load(&path).unwrap();
+        assert_eq!(loaded.fingerprint, catalog.fingerprint);
+        assert_eq!(loaded.entries, catalog.entries);
+
+        fs::write(&path, "ldraw catalog 1\t1234\n").unwrap();
+        assert_eq!(Catalog::load(&path).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        fs::write(&path, format!("{}\t1234\nonly\ttwo\n", MAGIC)).unwrap();
+        assert_eq!(Catalog::load(&path).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,467 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use super::cache::fnv1a;
+use super::document;
+
+/// The table used when there's no LDConfig.ldr, in the same
+/// `name code #value [options]` layout as the `!COLOUR` lines it came from.
+const BUILTIN_COLORS: &str = include_str!("../res/colors.txt");
+
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Clone, Debug)]
+pub enum LdrawColor {
+    Main,
+    Complement,
+    RGBA(f32, f32, f32, f32),
+}
+
+impl LdrawColor {
+    /// The color to use for `self` in a subfile that was referenced with
+    /// `parent` (whose edge color is `parent_edge`), so that Main and
+    /// Complement pick up the parent's colors.
+    pub fn resolve(&self, parent: &LdrawColor, parent_edge: &LdrawColor) -> Self {
+        match self {
+            Self::Main => parent.clone(),
+            Self::Complement => parent_edge.clone(),
+            _ => self.clone(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Finish {
+    Plain,
+    Chrome,
+    Pearlescent,
+    Rubber,
+    MatteMetallic,
+    Metal,
+    /// `MATERIAL` and its parameters, e.g. "GLITTER VALUE #923978 FRACTION 0.17".
+    Material(String),
+}
+
+/// A `0 !COLOUR` definition.
+#[derive(Clone, Debug)]
+pub struct ColorDefinition {
+    pub name: String,
+    pub code: u32,
+    pub value: [f32; 4],
+    pub edge: [f32; 4],
+    pub luminance: u8,
+    pub finish: Finish,
+}
+
+impl ColorDefinition {
+    pub fn color(&self) -> LdrawColor {
+        let [r, g, b, a] = self.value;
+        LdrawColor::RGBA(r, g, b, a)
+    }
+
+    pub fn edge_color(&self) -> LdrawColor {
+        let [r, g, b, a] = self.edge;
+        LdrawColor::RGBA(r, g, b, a)
+    }
+}
+
+fn parse_hex(s: &str) -> Option<[f32; 4]> {
+    let s = s.strip_prefix('#').or_else(|| s.strip_prefix("0x"))?;
+    let value = u32::from_str_radix(s, 16).ok()?;
+    Some([
+        ((value >> 16) & 0xff) as f32 / 255.0,
+        ((value >> 8) & 0xff) as f32 / 255.0,
+        (value & 0xff) as f32 / 255.0,
+        1.0,
+    ])
+}
+
+/// Expands a 12-bit `RGB` color, as used by the dithered direct colors.
+fn rgb12(value: u32, alpha: f32) -> [f32; 4] {
+    [
+        ((value >> 8) & 0xf) as f32 / 15.0,
+        ((value >> 4) & 0xf) as f32 / 15.0,
+        (value & 0xf) as f32 / 15.0,
+        alpha,
+    ]
+}
+
+/// Parses a direct color: `0x2RRGGBB` (opaque), `0x3RRGGBB` (transparent),
+/// or one of the legacy dithered forms `0x4RGBRGB` (the average of two
+/// colors), `0x5RGBxxx` and `0x6xxxRGB` (transparent) and `0x7xxxxxx`
+/// (invisible).
+fn parse_direct(code: &str) -> Option<[f32; 4]> {
+    let hex = code.strip_prefix("0x").or_else(|| code.strip_prefix("0X"))?;
+    if hex.len() != 7 {
+        return None;
+    }
+    let value = u32::from_str_radix(hex, 16).ok()?;
+    let rgb = parse_hex(&format!("#{:06X}", value & 0xffffff))?;
+    match value >> 24 {
+        2 => Some(rgb),
+        3 => Some([rgb[0], rgb[1], rgb[2], 0.5]),
+        4 => {
+            let (a, b) = (rgb12(value >> 12, 1.0), rgb12(value, 1.0));
+            Some([(a[0] + b[0]) / 2.0, (a[1] + b[1]) / 2.0, (a[2] + b[2]) / 2.0, 1.0])
+        }
+        5 => Some(rgb12(value >> 12, 0.5)),
+        6 => Some(rgb12(value, 0.5)),
+        7 => Some([0.0, 0.0, 0.0, 0.0]),
+        _ => None,
+    }
+}
+
+/// The edge color for colors that don't define one. Dark colors get a gray
+/// edge and everything else gets a near-black one, like in LDConfig.ldr.
+fn default_edge(value: [f32; 4]) -> [f32; 4] {
+    let [r, g, b, _] = value;
+    if 0.2126 * r + 0.7152 * g + 0.0722 * b < 0.17 {
+        [0.5, 0.5, 0.5, 1.0]
+    } else {
+        [0.2, 0.2, 0.2, 1.0]
+    }
+}
+
+/// How a definition names its edge color, which may be another code that
+/// is only known once the whole table has been read.
+enum EdgeRef {
+    Value([f32; 4]),
+    Code(u32),
+    Default,
+}
+
+/// Reads the options after a color's value: `ALPHA`, `LUMINANCE` and the
+/// finish keywords. Unknown options are ignored.
+fn parse_options(definition: &mut ColorDefinition, options: &[&str]) {
+    let mut i = 0;
+    while i < options.len() {
+        match options[i] {
+            "ALPHA" => {
+                if let Some(alpha) = options.get(i + 1).and_then(|a| a.parse::<u8>().ok()) {
+                    definition.value[3] = alpha as f32 / 255.0;
+                }
+                i += 1;
+            }
+            "LUMINANCE" => {
+                if let Some(luminance) = options.get(i + 1).and_then(|l| l.parse().ok()) {
+                    definition.luminance = luminance;
+                }
+                i += 1;
+            }
+            "CHROME" => definition.finish = Finish::Chrome,
+            "PEARLESCENT" => definition.finish = Finish::Pearlescent,
+            "RUBBER" => definition.finish = Finish::Rubber,
+            "MATTE_METALLIC" => definition.finish = Finish::MatteMetallic,
+            "METAL" => definition.finish = Finish::Metal,
+            "MATERIAL" => {
+                definition.finish = Finish::Material(options[i + 1..].join(" "));
+                break;
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+}
+
+/// The colors that codes on type 1-5 lines refer to.
+#[derive(Clone, Debug, Default)]
+pub struct ColorTable {
+    colors: HashMap<u32, ColorDefinition>,
+}
+
+impl ColorTable {
+    /// The table that's compiled in, for when there's no LDConfig.ldr.
+    pub fn builtin() -> Self {
+        let mut definitions = Vec::new();
+        for line in BUILTIN_COLORS.lines() {
+            let tokens: Vec<&str> = line.split_whitespace().collect();
+            if tokens.len() < 3 {
+                continue;
+            }
+            let (code, value) = match (tokens[1].parse(), parse_hex(tokens[2])) {
+                (Ok(code), Some(value)) => (code, value),
+                _ => continue,
+            };
+            let mut definition = ColorDefinition {
+                name: tokens[0].into(),
+                code,
+                value,
+                edge: default_edge(value),
+                luminance: 0,
+                finish: Finish::Plain,
+            };
+            parse_options(&mut definition, &tokens[3..]);
+            definitions.push((definition, EdgeRef::Default));
+        }
+        Self::from_definitions(definitions)
+    }
+
+    /// Reads the `0 !COLOUR` lines of an LDConfig.ldr. Other lines, and
+    /// definitions that are missing a name, code or value, are skipped.
+    pub fn parse(text: &str) -> Self {
+        let mut definitions = Vec::new();
+        for line in text.lines() {
+            let tokens: Vec<&str> = line.split_whitespace().collect();
+            if tokens.len() < 3 || tokens[0] != "0" || tokens[1] != "!COLOUR" {
+                continue;
+            }
+            let mut code = None;
+            let mut value = None;
+            let mut edge = EdgeRef::Default;
+            let mut options = Vec::new();
+            let mut i = 3;
+            while i < tokens.len() {
+                let argument = tokens.get(i + 1).copied().unwrap_or("");
+                match tokens[i] {
+                    "CODE" => code = argument.parse::<u32>().ok(),
+                    "VALUE" => value = parse_hex(argument),
+                    "EDGE" => {
+                        edge = match (parse_hex(argument), argument.parse()) {
+                            (Some(value), _) => EdgeRef::Value(value),
+                            (None, Ok(code)) => EdgeRef::Code(code),
+                            _ => EdgeRef::Default,
+                        }
+                    }
+                    _ => {
+                        options.extend_from_slice(&tokens[i..]);
+                        break;
+                    }
+                }
+                i += 2;
+            }
+            let (code, value) = match (code, value) {
+                (Some(code), Some(value)) => (code, value),
+                _ => continue,
+            };
+            let mut definition = ColorDefinition {
+                name: tokens[2].into(),
+                code,
+                value,
+                edge: default_edge(value),
+                luminance: 0,
+                finish: Finish::Plain,
+            };
+            parse_options(&mut definition, &options);
+            definitions.push((definition, edge));
+        }
+        Self::from_definitions(definitions)
+    }
+
+    /// Reads an LDConfig.ldr, decoding it as `document::decode` does.
+    pub fn load(path: &Path) -> io::Result<Self> {
+        Ok(Self::parse(&document::decode_normalized(&fs::read(path)?).0))
+    }
+
+    fn from_definitions(definitions: Vec<(ColorDefinition, EdgeRef)>) -> Self {
+        let values: HashMap<u32, [f32; 4]> = definitions.iter().map(|(d, _)| (d.code, d.value)).collect();
+        let mut colors = HashMap::new();
+        for (mut definition, edge) in definitions {
+            match edge {
+                EdgeRef::Value(value) => definition.edge = value,
+                EdgeRef::Code(code) => {
+                    if let Some(&value) = values.get(&code) {
+                        definition.edge = [value[0], value[1], value[2], 1.0];
+                    }
+                }
+                EdgeRef::Default => {}
+            }
+            colors.insert(definition.code, definition);
+        }
+        Self { colors }
+    }
+
+    pub fn get(&self, code: u32) -> Option<&ColorDefinition> {
+        self.colors.get(&code)
+    }
+
+    pub fn insert(&mut self, definition: ColorDefinition) {
+        self.colors.insert(definition.code, definition);
+    }
+
+    pub fn len(&self) -> usize {
+        self.colors.len()
+    }
+
+    /// A hash of every code's value and edge value, which is all that
+    /// parsed geometry takes from the table.
+    pub fn fingerprint(&self) -> u64 {
+        let mut codes: Vec<&u32> = self.colors.keys().collect();
+        codes.sort();
+        let mut bytes = Vec::new();
+        for code in codes {
+            let definition = &self.colors[code];
+            bytes.extend_from_slice(&code.to_le_bytes());
+            for value in definition.value.iter().chain(&definition.edge) {
+                bytes.extend_from_slice(&value.to_bits().to_le_bytes());
+            }
+        }
+        fnv1a(bytes)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.colors.is_empty()
+    }
+
+    /// The color and edge color for a color code from a type 1-5 line, or
+    /// `None` if the code isn't in the table or a valid direct color.
+    pub fn lookup(&self, code: &str) -> Option<(LdrawColor, LdrawColor)> {
+        let value = match code {
+            "16" => return Some((LdrawColor::Main, LdrawColor::Complement)),
+            "24" => return Some((LdrawColor::Complement, LdrawColor::Complement)),
+            _ if code.starts_with("0x") || code.starts_with("0X") => parse_direct(code)?,
+            _ => {
+                let code = code.parse().ok()?;
+                match self.get(code) {
+                    Some(definition) => return Some((definition.color(), definition.edge_color())),
+                    None => self.dithered(code)?,
+                }
+            }
+        };
+        let [r, g, b, a] = value;
+        let [er, eg, eb, ea] = default_edge(value);
+        Some((LdrawColor::RGBA(r, g, b, a), LdrawColor::RGBA(er, eg, eb, ea)))
+    }
+
+    /// The legacy dithered codes 256-511 mix two of the colors 0-15:
+    /// code 256 + 16 * a + b is the average of a and b.
+    fn dithered(&self, code: u32) -> Option<[f32; 4]> {
+        if !(256..512).contains(&code) {
+            return None;
+        }
+        let a = self.get((code - 256) / 16)?.value;
+        let b = self.get((code - 256) % 16)?.value;
+        Some([(a[0] + b[0]) / 2.0, (a[1] + b[1]) / 2.0, (a[2] + b[2]) / 2.0, (a[3] + b[3]) / 2.0])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(actual: [f32; 4], expected: [f32; 4]) {
+        for (a, e) in actual.iter().zip(&expected) {
+            assert!((a - e).abs() < 1e-6, "{:?} != {:?}", actual, expected);
+        }
+    }
+
+    fn rgba(color: LdrawColor) -> [f32; 4] {
+        match color {
+            LdrawColor::RGBA(r, g, b, a) => [r, g, b, a],
+            color => panic!("unexpected {:?}", color),
+        }
+    }
+
+    #[test]
+    fn colour_lines() {
+        let table = ColorTable::parse(
+            "0 LDraw.org Configuration File\n\
+             0 !COLOUR Black CODE 0 VALUE #1B2A34 EDGE #808080\n\
+             0 !COLOUR Blue CODE 1 VALUE #1E5AA8 EDGE 0\n\
+             0 !COLOUR Trans_Clear CODE 47 VALUE #FCFCFC EDGE #C3C3C3 ALPHA 128\n\
+             0 !COLOUR Glow_In_Dark_Opaque CODE 21 VALUE #E0FFB0 EDGE #A4C2A4 ALPHA 250 LUMINANCE 15\n\
+             0 !COLOUR Chrome_Gold CODE 334 VALUE #DFC176 EDGE #F5F5F5 CHROME\n\
+             0 !COLOUR Glitter_Trans_Clear CODE 117 VALUE #FFFFFF EDGE #C3C3C3 ALPHA 128 MATERIAL GLITTER VALUE #FFFFFF FRACTION 0.08 VFRACTION 0.1 SIZE 1\n\
+             0 !COLOUR Rubber_Black CODE 256 VALUE #212121 EDGE #595959 RUBBER\n",
+        );
+        assert_eq!(table.len(), 7);
+
+        let black = table.get(0).unwrap();
+        assert_eq!(black.name, "Black");
+        assert_close(black.value, [0x1b as f32 / 255.0, 0x2a as f32 / 255.0, 0x34 as f32 / 255.0, 1.0]);
+        assert_close(black.edge, [128.0 / 255.0, 128.0 / 255.0, 128.0 / 255.0, 1.0]);
+        assert_eq!(black.finish, Finish::Plain);
+        // An edge given by code is that code's value.
+        assert_close(table.get(1).unwrap().edge, black.value);
+
+        assert_close(table.get(47).unwrap().value, [252.0 / 255.0, 252.0 / 255.0, 252.0 / 255.0, 128.0 / 255.0]);
+        let glow = table.get(21).unwrap();
+        assert_eq!((glow.value[3], glow.luminance), (250.0 / 255.0, 15));
+        assert_eq!(table.get(334).unwrap().finish, Finish::Chrome);
+        assert_eq!(table.get(256).unwrap().finish, Finish::Rubber);
+        let glitter = table.get(117).unwrap();
+        assert_eq!(glitter.value[3], 128.0 / 255.0);
+        assert_eq!(
+            glitter.finish,
+            Finish::Material("GLITTER VALUE #FFFFFF FRACTION 0.08 VFRACTION 0.1 SIZE 1".into())
+        );
+    }
+
+    #[test]
+    fn malformed_colour_lines_are_skipped() {
+        let table = ColorTable::parse(
+            "0 !COLOUR No_Code VALUE #FFFFFF EDGE #333333\n\
+             0 !COLOUR No_Value CODE 1 EDGE #333333\n\
+             0 !COLOUR Bad_Code CODE one VALUE #FFFFFF\n\
+             0 !COLOUR Bad_Value CODE 2 VALUE white\n\
+             0 !COLOUR\n\
+             1 !COLOUR Not_Meta CODE 3 VALUE #FFFFFF\n\
+             0 !COLOUR Bad_Edge CODE 4 VALUE #FFFFFF EDGE grey\n\
+             0 !COLOUR Missing_Edge CODE 5 VALUE #000000 EDGE 99\n",
+        );
+        assert_eq!(table.len(), 2);
+        // Edges that can't be read or refer to missing codes get the default.
+        assert_close(table.get(4).unwrap().edge, [0.2, 0.2, 0.2, 1.0]);
+        assert_close(table.get(5).unwrap().edge, [0.5, 0.5, 0.5, 1.0]);
+    }
+
+    #[test]
+    fn default_edges_depend_on_brightness() {
+        assert_eq!(default_edge([0.0, 0.0, 0.0, 1.0]), [0.5, 0.5, 0.5, 1.0]);
+        // Pure blue is dark, but pure green isn't.
+        assert_eq!(default_edge([0.0, 0.0, 1.0, 1.0]), [0.5, 0.5, 0.5, 1.0]);
+        assert_eq!(default_edge([0.0, 1.0, 0.0, 1.0]), [0.2, 0.2, 0.2, 1.0]);
+        assert_eq!(default_edge([1.0, 1.0, 1.0, 0.5]), [0.2, 0.2, 0.2, 1.0]);
+    }
+
+    #[test]
+    fn direct_colors() {
+        for &(code, expected) in &[
+            ("0x2FF0000", Some([1.0, 0.0, 0.0, 1.0])),
+            ("0X200FF80", Some([0.0, 1.0, 128.0 / 255.0, 1.0])),
+            ("0x30000FF", Some([0.0, 0.0, 1.0, 0.5])),
+            ("0x4F000F0", Some([0.5, 0.5, 0.0, 1.0])),
+            ("0x5F00123", Some([1.0, 0.0, 0.0, 0.5])),
+            ("0x6123F00", Some([1.0, 0.0, 0.0, 0.5])),
+            ("0x7123456", Some([0.0, 0.0, 0.0, 0.0])),
+            ("0x1FF0000", None),
+            ("0x8FF0000", None),
+            ("0x2FF00", None),
+            ("0x2FF00000", None),
+            ("0x2GG0000", None),
+            ("2FF0000", None),
+        ] {
+            match (parse_direct(code), expected) {
+                (Some(value), Some(expected)) => assert_close(value, expected),
+                (value, expected) => assert_eq!(value, expected, "{}", code),
+            }
+        }
+    }
+
+    #[test]
+    fn direct_colors_get_a_default_edge() {
+        let table = ColorTable::default();
+        let (color, edge) = table.lookup("0x2000000").unwrap();
+        assert_close(rgba(color), [0.0, 0.0, 0.0, 1.0]);
+        assert_close(rgba(edge), [0.5, 0.5, 0.5, 1.0]);
+        assert!(table.lookup("0x8000000").is_none());
+    }
+
+    #[test]
+    fn dithered_colors_average_two_of_the_first_sixteen() {
+        let table = ColorTable::parse(
+            "0 !COLOUR Black CODE 0 VALUE #000000 EDGE #595959\n\
+             0 !COLOUR White CODE 15 VALUE #FFFFFF EDGE #333333 ALPHA 0\n",
+        );
+        // 256 + 16 * 0 + 15
+        let (color, edge) = table.lookup("271").unwrap();
+        assert_close(rgba(color), [0.5, 0.5, 0.5, 0.5]);
+        assert_close(rgba(edge), [0.2, 0.2, 0.2, 1.0]);
+        assert_close(table.dithered(256).unwrap(), [0.0, 0.0, 0.0, 1.0]);
+        // Colors 1-14 aren't defined, and 512 is past the dithered codes.
+        assert!(table.dithered(257).is_none());
+        assert!(table.dithered(255).is_none());
+        assert!(table.dithered(512).is_none());
+        assert!(table.lookup("300").is_none());
+    }
+}
@@ -0,0 +1,542 @@
+use cgmath::{Matrix4, Point3};
+use std::borrow::Cow;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::ops::Range;
+use std::path::Path;
+
+/// Type 0 keywords that are meta commands even though they don't start
+/// with `!`. Any other type 0 line is a comment.
+const META_KEYWORDS: &[&str] = &[
+    "BFC", "STEP", "ROTSTEP", "FILE", "NOFILE", "CLEAR", "PAUSE", "PRINT", "WRITE", "SAVE", "Name:", "Author:",
+];
+
+/// Decodes the bytes of an LDraw file. Files should be UTF-8, but older
+/// ones are often Latin-1, e.g. for accented author names, so any bytes that
+/// aren't valid UTF-8 are read as Latin-1 instead. Returns the offset of the
+/// first such byte, if there was one.
+pub fn decode(data: &[u8]) -> (String, Option<usize>) {
+    let invalid = match std::str::from_utf8(data) {
+        Ok(text) => return (text.into(), None),
+        Err(e) => e.valid_up_to(),
+    };
+    let mut text = String::with_capacity(data.len());
+    for chunk in data.utf8_chunks() {
+        text.push_str(chunk.valid());
+        text.extend(chunk.invalid().iter().map(|&b| b as char));
+    }
+    (text, Some(invalid))
+}
+
+/// Text as parsing expects it: without a byte order mark, and with `\r\n`
+/// and `\r` line endings turned into `\n`.
+pub fn normalize(text: &str) -> Cow<'_, str> {
+    let text = text.strip_prefix('\u{feff}').unwrap_or(text);
+    if text.contains('\r') {
+        Cow::Owned(text.replace("\r\n", "\n").replace('\r', "\n"))
+    } else {
+        Cow::Borrowed(text)
+    }
+}
+
+/// Decodes a file to be parsed rather than edited: as `decode` does, and
+/// then `normalize`d. The offset is still into `data`.
+pub fn decode_normalized(data: &[u8]) -> (String, Option<usize>) {
+    let (mut text, invalid) = decode(data);
+    if text.starts_with('\u{feff}') || text.contains('\r') {
+        text = normalize(&text).into_owned();
+    }
+    (text, invalid)
+}
+
+/// What a line of an LDraw file does.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Command {
+    /// A blank line.
+    Empty,
+    /// `0 // text`, or any type 0 line that isn't a meta command, like the
+    /// title.
+    Comment(String),
+    /// `0 KEYWORD arguments`, e.g. `0 BFC CERTIFY CCW` or `0 !LDRAW_ORG Part`.
+    Meta { keyword: String, arguments: String },
+    /// Type 1.
+    SubfileReference { color: String, transform: Matrix4<f32>, name: String },
+    /// Type 2.
+    Line { color: String, points: [Point3<f32>; 2] },
+    /// Type 3.
+    Triangle { color: String, points: [Point3<f32>; 3] },
+    /// Type 4.
+    Quad { color: String, points: [Point3<f32>; 4] },
+    /// Type 5.
+    OptionalLine { color: String, points: [Point3<f32>; 2], controls: [Point3<f32>; 2] },
+    /// Anything else, including lines with missing tokens or bad numbers.
+    /// These are kept as they are.
+    Invalid,
+}
+
+/// A line of an LDraw file, with its tokens and the whitespace around them
+/// kept exactly as they were written.
+#[derive(Clone, Debug)]
+pub struct DocumentLine {
+    tokens: Vec<String>,
+    /// The whitespace before each token, then the whitespace after the last
+    /// one, so there's always one more of these than there are tokens.
+    whitespace: Vec<String>,
+    /// `"\n"`, `"\r\n"`, `"\r"`, or nothing on a last line without one.
+    pub ending: String,
+    command: Command,
+}
+
+/// Formats a number the way LDraw files usually do: rounded to 6 decimal
+/// places, with no trailing zeros and no negative zero.
+fn format_number(value: f32) -> String {
+    let text = format!("{:.6}", value);
+    let text = text.trim_end_matches('0').trim_end_matches('.');
+    if text == "-0" {
+        "0".into()
+    } else {
+        text.into()
+    }
+}
+
+impl DocumentLine {
+    /// A line from its text, which shouldn't include a line ending.
+    pub fn new(text: &str, ending: &str) -> Self {
+        let mut tokens = Vec::new();
+        let mut whitespace = Vec::new();
+        let mut rest = text;
+        loop {
+            let start = rest.find(|c: char| !c.is_whitespace()).unwrap_or(rest.len());
+            whitespace.push(rest[..start].to_string());
+            rest = &rest[start..];
+            if rest.is_empty() {
+                break;
+            }
+            let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+            tokens.push(rest[..end].to_string());
+            rest = &rest[end..];
+        }
+        let mut line = Self {
+            tokens,
+            whitespace,
+            ending: ending.into(),
+            command: Command::Empty,
+        };
+        line.command = line.parse_command();
+        line
+    }
+
+    pub fn command(&self) -> &Command {
+        &self.command
+    }
+
+    pub fn tokens(&self) -> &[String] {
+        &self.tokens
+    }
+
+    /// The text of the line, without its ending.
+    pub fn text(&self) -> String {
+        let mut text = String::new();
+        for (whitespace, token) in self.whitespace.iter().zip(&self.tokens) {
+            text.push_str(whitespace);
+            text.push_str(token);
+        }
+        text.push_str(self.whitespace.last().unwrap());
+        text
+    }
+
+    /// The text from token `index` to the end of the last token.
+    fn rest(&self, index: usize) -> String {
+        let mut rest = String::new();
+        for (i, token) in self.tokens.iter().enumerate().skip(index) {
+            if i > index {
+                rest.push_str(&self.whitespace[i]);
+            }
+            rest.push_str(token);
+        }
+        rest
+    }
+
+    fn points<const N: usize>(&self, start: usize) -> Option<[Point3<f32>; N]> {
+        let mut points = [Point3::new(0.0, 0.0, 0.0); N];
+        for (i, point) in points.iter_mut().enumerate() {
+            let coordinate = |j: usize| self.tokens[start + i * 3 + j].parse::<f32>().ok();
+            *point = Point3::new(coordinate(0)?, coordinate(1)?, coordinate(2)?);
+        }
+        Some(points)
+    }
+
+    fn parse_command(&self) -> Command {
+        let tokens = &self.tokens;
+        let expect = |count: usize| tokens.len() >= count;
+        let color = || tokens[1].clone();
+        let command = match tokens.first().map(String::as_str) {
+            None => Some(Command::Empty),
+            Some("0") => Some(match tokens.get(1).map(String::as_str) {
+                None => Command::Comment(String::new()),
+                Some("//") => Command::Comment(self.rest(2)),
+                Some(keyword) if keyword.starts_with('!') || META_KEYWORDS.contains(&keyword) => Command::Meta {
+                    keyword: keyword.into(),
+                    arguments: self.rest(2),
+                },
+                Some(_) => Command::Comment(self.rest(1)),
+            }),
+            Some("1") if expect(15) => self.points::<4>(2).map(|[p, a, b, c]| Command::SubfileReference {
+                color: color(),
+                #[rustfmt::skip]
+                transform: Matrix4::new(
+                    a.x, b.x, c.x, 0.0,
+                    a.y, b.y, c.y, 0.0,
+                    a.z, b.z, c.z, 0.0,
+                    p.x, p.y, p.z, 1.0,
+                ),
+                name: self.rest(14),
+            }),
+            Some("2") if expect(8) => self.points(2).map(|points| Command::Line { color: color(), points }),
+            Some("3") if expect(11) => self.points(2).map(|points| Command::Triangle { color: color(), points }),
+            Some("4") if expect(14) => self.points(2).map(|points| Command::Quad { color: color(), points }),
+            Some("5") if expect(14) => self.points::<4>(2).map(|[a, b, c, d]| Command::OptionalLine {
+                color: color(),
+                points: [a, b],
+                controls: [c, d],
+            }),
+            Some(_) => None,
+        };
+        command.unwrap_or(Command::Invalid)
+    }
+
+    /// Replaces the numbers starting at token `start`, keeping the whitespace
+    /// around them.
+    fn set_numbers(&mut self, start: usize, values: &[f32]) {
+        for (i, value) in values.iter().enumerate() {
+            self.tokens[start + i] = format_number(*value);
+        }
+        self.command = self.parse_command();
+    }
+
+    /// Changes the color code of a type 1-5 line. Returns false, changing
+    /// nothing, for other lines.
+    pub fn set_color(&mut self, color: &str) -> bool {
+        match self.command {
+            Command::SubfileReference { .. }
+            | Command::Line { .. }
+            | Command::Triangle { .. }
+            | Command::Quad { .. }
+            | Command::OptionalLine { .. } => {
+                self.tokens[1] = color.into();
+                self.command = self.parse_command();
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Changes the position and orientation of a type 1 line. Returns false,
+    /// changing nothing, for other lines.
+    pub fn set_transform(&mut self, transform: &Matrix4<f32>) -> bool {
+        if let Command::SubfileReference { .. } = self.command {
+            let m = transform;
+            #[rustfmt::skip]
+            let values = [
+                m.w.x, m.w.y, m.w.z,
+                m.x.x, m.y.x, m.z.x,
+                m.x.y, m.y.y, m.z.y,
+                m.x.z, m.y.z, m.z.z,
+            ];
+            self.set_numbers(2, &values);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Changes the points of a type 2-5 line, given in the order they're
+    /// written (for optional lines, the two points and then the two control
+    /// points). Returns false, changing nothing, for other lines or the
+    /// wrong number of points.
+    pub fn set_points(&mut self, points: &[Point3<f32>]) -> bool {
+        let count = match self.command {
+            Command::Line { .. } => 2,
+            Command::Triangle { .. } => 3,
+            Command::Quad { .. } | Command::OptionalLine { .. } => 4,
+            _ => return false,
+        };
+        if points.len() != count {
+            return false;
+        }
+        let values: Vec<f32> = points.iter().flat_map(|p| vec![p.x, p.y, p.z]).collect();
+        self.set_numbers(2, &values);
+        true
+    }
+}
+
+/// A whole LDraw file, line by line, which can be edited and written back.
+/// Writing a document that hasn't been changed gives back exactly the text
+/// it was parsed from, or the bytes it was read from.
+#[derive(Clone, Debug, Default)]
+pub struct LdrawDocument {
+    /// Whether the text started with a byte order mark.
+    pub bom: bool,
+    /// Whether the file wasn't valid UTF-8 and was read as Latin-1, which is
+    /// how it's written back.
+    pub latin1: bool,
+    pub lines: Vec<DocumentLine>,
+}
+
+impl LdrawDocument {
+    pub fn parse(text: &str) -> Self {
+        let (bom, mut rest) = match text.strip_prefix('\u{feff}') {
+            Some(rest) => (true, rest),
+            None => (false, text),
+        };
+        let mut lines = Vec::new();
+        while !rest.is_empty() {
+            let end = rest.find(['\n', '\r']).unwrap_or(rest.len());
+            let ending_length = match &rest[end..] {
+                e if e.starts_with("\r\n") => 2,
+                "" => 0,
+                _ => 1,
+            };
+            lines.push(DocumentLine::new(&rest[..end], &rest[end..end + ending_length]));
+            rest = &rest[end + ending_length..];
+        }
+        Self { bom, latin1: false, lines }
+    }
+
+    /// Parses the bytes of a file as UTF-8 or, if they aren't valid UTF-8,
+    /// as Latin-1.
+    pub fn from_bytes(data: &[u8]) -> Self {
+        match std::str::from_utf8(data) {
+            Ok(text) => Self::parse(text),
+            Err(_) => Self {
+                latin1: true,
+                ..Self::parse(&data.iter().map(|&b| b as char).collect::<String>())
+            },
+        }
+    }
+
+    /// The document's text, encoded as it was read. Latin-1 documents with
+    /// characters that Latin-1 doesn't have are written as UTF-8 instead.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let text = self.to_string();
+        if self.latin1 && text.chars().all(|c| (c as u32) < 0x100) {
+            text.chars().map(|c| c as u8).collect()
+        } else {
+            text.into_bytes()
+        }
+    }
+
+    /// Reads a file as `from_bytes` does.
+    pub fn load(path: &Path) -> io::Result<Self> {
+        Ok(Self::from_bytes(&fs::read(path)?))
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        fs::write(path, self.to_bytes())
+    }
+
+    /// Adds a line, ending it the same way as the last line that has an
+    /// ending. A last line without one gets one too.
+    pub fn push_line(&mut self, text: &str) {
+        let ending = self
+            .lines
+            .iter()
+            .rev()
+            .map(|line| &line.ending)
+            .find(|ending| !ending.is_empty())
+            .cloned()
+            .unwrap_or_else(|| "\n".into());
+        if let Some(last) = self.lines.last_mut() {
+            if last.ending.is_empty() {
+                last.ending = ending.clone();
+            }
+        }
+        self.lines.push(DocumentLine::new(text, &ending));
+    }
+
+    /// The sections of an MPD, as the name from each `0 FILE` line and the
+    /// lines that follow it up to the next `0 FILE` or `0 NOFILE`. Documents
+    /// that aren't MPDs have no sections.
+    pub fn sections(&self) -> Vec<(String, Range<usize>)> {
+        let mut sections: Vec<(String, Range<usize>)> = Vec::new();
+        let mut open = false;
+        for (i, line) in self.lines.iter().enumerate() {
+            if let Command::Meta { keyword, arguments } = &line.command {
+                if keyword == "FILE" || keyword == "NOFILE" {
+                    if let (true, Some(last)) = (open, sections.last_mut()) {
+                        last.1.end = i;
+                    }
+                    open = keyword == "FILE";
+                    if open {
+                        sections.push((arguments.clone(), i..self.lines.len()));
+                    }
+                }
+            }
+        }
+        sections
+    }
+}
+
+impl fmt::Display for LdrawDocument {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.bom {
+            write!(f, "\u{feff}")?;
+        }
+        for line in &self.lines {
+            write!(f, "{}{}", line.text(), line.ending)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cgmath::{Deg, Vector3};
+
+    fn round_trip(text: &str) {
+        assert_eq!(LdrawDocument::parse(text).to_string(), text);
+    }
+
+    #[test]
+    fn round_trip_keeps_line_endings() {
+        round_trip("0 Title\r\n0 Name: a.ldr\r\n3 16 0 0 0 1 0 0 0 1 0\r\n");
+        round_trip("0 Title\r0 Name: a.ldr\r3 16 0 0 0 1 0 0 0 1 0\r");
+        round_trip("0 Title\n0 Name: a.ldr\r\n3 16 0 0 0 1 0 0 0 1 0\r");
+        round_trip("0 Title\n3 16 0 0 0 1 0 0 0 1 0");
+        round_trip("\n\r\n\r");
+        round_trip("");
+    }
+
+    #[test]
+    fn round_trip_keeps_bom_and_whitespace() {
+        round_trip("\u{feff}0 Title\n");
+        round_trip("0\tTitle\twith\ttabs\n");
+        round_trip("  1  16\t0 0 0  1 0 0 0 1 0 0 0 1   3001 brick.dat  \n");
+        round_trip("0 Title   \n   \n\t\n");
+
+        let document = LdrawDocument::parse("\u{feff}0 Title\n");
+        assert!(document.bom);
+        assert_eq!(document.lines[0].text(), "0 Title");
+    }
+
+    #[test]
+    fn lines_without_a_final_ending() {
+        let document = LdrawDocument::parse("0 a\r\n0 b");
+        assert_eq!(document.lines.len(), 2);
+        assert_eq!(document.lines[0].ending, "\r\n");
+        assert_eq!(document.lines[1].ending, "");
+
+        let mut document = document;
+        document.push_line("0 c");
+        assert_eq!(document.to_string(), "0 a\r\n0 b\r\n0 c\r\n");
+    }
+
+    #[test]
+    fn commands() {
+        let document = LdrawDocument::parse("0 // note\n0 BFC CERTIFY CCW\n0 Title\n1 16 0 0 0 1 0 0 0 1 0 0 0 1 a b.dat\n2 24 0 0\n");
+        assert_eq!(*document.lines[0].command(), Command::Comment("note".into()));
+        assert_eq!(
+            *document.lines[1].command(),
+            Command::Meta { keyword: "BFC".into(), arguments: "CERTIFY CCW".into() }
+        );
+        assert_eq!(*document.lines[2].command(), Command::Comment("Title".into()));
+        match document.lines[3].command() {
+            Command::SubfileReference { name, .. } => assert_eq!(name, "a b.dat"),
+            command => panic!("unexpected {:?}", command),
+        }
+        assert_eq!(*document.lines[4].command(), Command::Invalid);
+    }
+
+    #[test]
+    fn set_color_keeps_the_rest_of_the_line() {
+        let mut line = DocumentLine::new("3\t16   0 0 0  1 0 0 0 1 0  ", "\r\n");
+        assert!(line.set_color("4"));
+        assert_eq!(line.text(), "3\t4   0 0 0  1 0 0 0 1 0  ");
+        assert_eq!(line.ending, "\r\n");
+        match line.command() {
+            Command::Triangle { color, .. } => assert_eq!(color, "4"),
+            command => panic!("unexpected {:?}", command),
+        }
+
+        let mut comment = DocumentLine::new("0 Title", "\n");
+        assert!(!comment.set_color("4"));
+        assert_eq!(comment.text(), "0 Title");
+    }
+
+    #[test]
+    fn set_transform_keeps_the_name_and_whitespace() {
+        let mut line = DocumentLine::new("1  16 0 0 0 1 0 0 0 1 0 0 0 1  3001 brick.dat", "");
+        let transform = Matrix4::from_translation(Vector3::new(10.0, -24.0, 0.5)) * Matrix4::from_angle_y(Deg(90.0));
+        assert!(line.set_transform(&transform));
+        // cos(90°) isn't exactly 0 as an f32, but it rounds to it.
+        assert_eq!(line.text(), "1  16 10 -24 0.5 0 0 1 0 1 0 -1 0 0  3001 brick.dat");
+        match line.command() {
+            Command::SubfileReference { transform: parsed, name, .. } => {
+                assert_eq!(name, "3001 brick.dat");
+                for (a, b) in AsRef::<[f32; 16]>::as_ref(parsed).iter().zip(AsRef::<[f32; 16]>::as_ref(&transform)) {
+                    assert!((a - b).abs() < 1e-6);
+                }
+            }
+            command => panic!("unexpected {:?}", command),
+        }
+
+        let mut triangle = DocumentLine::new("3 16 0 0 0 1 0 0 0 1 0", "\n");
+        assert!(!triangle.set_transform(&transform));
+        assert_eq!(triangle.text(), "3 16 0 0 0 1 0 0 0 1 0");
+    }
+
+    #[test]
+    fn edits_round_trip_the_other_lines() {
+        let text = "\u{feff}0 Model\r\n1 16 0 0 0 1 0 0 0 1 0 0 0 1 a.dat\r\n0 STEP\r\n";
+        let mut document = LdrawDocument::parse(text);
+        assert!(document.lines[1].set_color("4"));
+        assert_eq!(document.to_string(), text.replace("1 16 ", "1 4 "));
+    }
+
+    #[test]
+    fn numbers_are_rounded_and_trimmed() {
+        for (value, text) in [
+            (0.0, "0"),
+            (-0.0, "0"),
+            (-0.00000004371139, "0"),
+            (1.0, "1"),
+            (-24.0, "-24"),
+            (0.5, "0.5"),
+            (std::f32::consts::FRAC_1_SQRT_2, "0.707107"),
+            (1.0 / 3.0, "0.333333"),
+            (-2.0 / 3.0, "-0.666667"),
+            (1234.5, "1234.5"),
+        ] {
+            assert_eq!(format_number(value), text);
+        }
+    }
+
+    #[test]
+    fn latin_1_documents_are_written_back_as_latin_1() {
+        let data = b"0 Title\r\n0 Author: Jos\xe9 Garc\xeda\r\n3 16 0 0 0 1 0 0 0 1 0\r\n";
+        let mut document = LdrawDocument::from_bytes(data);
+        assert!(document.latin1);
+        assert_eq!(document.lines[1].text(), "0 Author: José García");
+        assert_eq!(document.to_bytes(), data);
+
+        assert!(document.lines[2].set_color("4"));
+        assert_eq!(document.to_bytes(), &b"0 Title\r\n0 Author: Jos\xe9 Garc\xeda\r\n3 4 0 0 0 1 0 0 0 1 0\r\n"[..]);
+
+        document.push_line("0 // \u{2713}");
+        assert_eq!(std::str::from_utf8(&document.to_bytes()).unwrap().lines().last(), Some("0 // \u{2713}"));
+
+        let utf8 = "0 Author: José\n".as_bytes();
+        let document = LdrawDocument::from_bytes(utf8);
+        assert!(!document.latin1);
+        assert_eq!(document.to_bytes(), utf8);
+    }
+
+    #[test]
+    fn decode_reads_invalid_bytes_as_latin_1() {
+        assert_eq!(decode(b"0 Author: Jos\xe9\n"), ("0 Author: José\n".into(), Some(13)));
+        assert_eq!(decode("0 Author: José\n".as_bytes()), ("0 Author: José\n".into(), None));
+        assert_eq!(decode_normalized(b"\xef\xbb\xbf0 a\r\n0 b\r0 c"), ("0 a\n0 b\n0 c".into(), None));
+    }
+}
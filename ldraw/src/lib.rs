@@ -0,0 +1,12 @@
+//! Reading LDraw files and libraries into geometry, without any windowing
+//! or graphics dependencies, so that it can be used on machines without a
+//! display.
+
+pub mod cache;
+pub mod catalog;
+pub mod colors;
+pub mod document;
+pub mod library;
+pub mod mesh;
+pub mod normals;
+pub mod parser;
@@ -0,0 +1,506 @@
+use std::collections::HashMap;
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Component, Path, PathBuf};
+use std::sync::Mutex;
+use zip::ZipArchive;
+
+use super::cache::{self, fnv1a, Stamp};
+
+/// The folders under each library root that subfile names are relative to,
+/// in search order. References normally include `s/`, `48/` or `8/`
+/// themselves, but plenty of older files leave them out.
+const SUBDIRECTORIES: &[&str] = &["", "parts", "parts/s", "p", "p/48", "p/8", "models"];
+
+/// Somewhere library files can be read from. Names are matched
+/// case-insensitively where the backend supports it, as libraries are
+/// distributed for case-insensitive filesystems.
+pub trait LibrarySource: Send + Sync {
+    /// The path of `relative` in this source, if it exists. This is the path
+    /// diagnostics refer to and the one to pass to `read`.
+    fn find(&self, relative: &Path) -> Option<PathBuf>;
+
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>>;
+
+    /// The paths of the files directly in `directory`, e.g. `parts`, in no
+    /// particular order.
+    fn list(&self, directory: &Path) -> Vec<PathBuf>;
+
+    /// The folder or archive the source reads from, which tells sources
+    /// apart. Sources that aren't on disk have none.
+    fn location(&self) -> Option<&Path> {
+        None
+    }
+
+    /// The size and modification time of a file found by `find`. Sources
+    /// that aren't on disk can't stamp their files.
+    fn stamp(&self, path: &Path) -> Option<Stamp> {
+        self.location()?;
+        cache::stamp(path)
+    }
+}
+
+/// The name an entry is looked up by: lowercase, with `/` separators and
+/// no leading `./`.
+fn normalize(relative: &Path) -> String {
+    let name = relative.to_string_lossy().to_lowercase().replace('\\', "/");
+    name.trim_start_matches("./").into()
+}
+
+/// A library folder on disk.
+#[derive(Debug)]
+pub struct DirectorySource {
+    root: PathBuf,
+    /// Whether to fall back to a case-insensitive match when a name doesn't
+    /// exist exactly.
+    pub case_insensitive: bool,
+    /// Lowercased names of the entries of each folder that has needed a
+    /// case-insensitive lookup.
+    listings: Mutex<HashMap<PathBuf, HashMap<String, PathBuf>>>,
+}
+
+impl DirectorySource {
+    pub fn new<P: Into<PathBuf>>(root: P) -> Self {
+        Self {
+            root: root.into(),
+            case_insensitive: true,
+            listings: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Joins `relative` onto the root, matching each component that doesn't
+    /// exist as written case-insensitively if that's enabled.
+    fn resolve(&self, relative: &Path) -> Option<PathBuf> {
+        let exact = self.root.join(relative);
+        if !self.case_insensitive || exact.exists() {
+            return Some(exact);
+        }
+        let mut path = self.root.clone();
+        for component in relative.components() {
+            let name = match component {
+                Component::Normal(name) => name,
+                _ => {
+                    path.push(component);
+                    continue;
+                }
+            };
+            let next = path.join(name);
+            path = if next.exists() {
+                next
+            } else {
+                self.find_entry(&path, &name.to_string_lossy().to_lowercase())?
+            };
+        }
+        Some(path)
+    }
+
+    fn find_entry(&self, directory: &Path, lowercase_name: &str) -> Option<PathBuf> {
+        let mut listings = self.listings.lock().unwrap();
+        let listing = listings.entry(directory.into()).or_insert_with(|| {
+            let directory = if directory.as_os_str().is_empty() { Path::new(".") } else { directory };
+            fs::read_dir(directory)
+                .into_iter()
+                .flatten()
+                .flatten()
+                .map(|entry| (entry.file_name().to_string_lossy().to_lowercase(), entry.path()))
+                .collect()
+        });
+        listing.get(lowercase_name).cloned()
+    }
+}
+
+impl LibrarySource for DirectorySource {
+    fn location(&self) -> Option<&Path> {
+        Some(&self.root)
+    }
+
+    fn find(&self, relative: &Path) -> Option<PathBuf> {
+        self.resolve(relative).filter(|path| path.is_file())
+    }
+
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        fs::read(path)
+    }
+
+    fn list(&self, directory: &Path) -> Vec<PathBuf> {
+        self.resolve(directory)
+            .and_then(|directory| fs::read_dir(directory).ok())
+            .into_iter()
+            .flatten()
+            .flatten()
+            .map(|entry| entry.path())
+            .filter(|path| path.is_file())
+            .collect()
+    }
+}
+
+/// A zipped library, such as the distributed complete.zip, read without
+/// extracting it. Entries are relative to the folder holding LDConfig.ldr
+/// (`ldraw/` in complete.zip), and their paths are shown as if the archive
+/// were a folder.
+pub struct ZipSource {
+    path: PathBuf,
+    /// Open handles to the archive that aren't being read from. Each read
+    /// takes one, or opens another if they're all in use, so that files can
+    /// be read in parallel.
+    archives: Mutex<Vec<ZipArchive<File>>>,
+    /// Archive index of each entry by its normalized name.
+    entries: HashMap<String, usize>,
+}
+
+impl ZipSource {
+    pub fn open<P: Into<PathBuf>>(path: P) -> io::Result<Self> {
+        let path = path.into();
+        let mut archive = ZipArchive::new(File::open(&path)?)?;
+        let names: Vec<String> = archive.file_names().map(|name| name.to_lowercase()).collect();
+        let prefix = names
+            .iter()
+            .find(|name| *name == "ldconfig.ldr" || name.ends_with("/ldconfig.ldr"))
+            .map(|name| name[..name.len() - "ldconfig.ldr".len()].to_string())
+            .unwrap_or_default();
+        let mut entries = HashMap::new();
+        for i in 0..archive.len() {
+            let name = archive.by_index(i)?.name().to_lowercase();
+            if let Some(name) = name.strip_prefix(&prefix) {
+                if !name.ends_with('/') {
+                    entries.insert(name.to_string(), i);
+                }
+            }
+        }
+        Ok(Self {
+            path,
+            archives: Mutex::new(vec![archive]),
+            entries,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+impl LibrarySource for ZipSource {
+    fn location(&self) -> Option<&Path> {
+        Some(&self.path)
+    }
+
+    fn find(&self, relative: &Path) -> Option<PathBuf> {
+        let name = normalize(relative);
+        self.entries.get(&name).map(|_| self.path.join(name))
+    }
+
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        let name = path
+            .strip_prefix(&self.path)
+            .map(normalize)
+            .map_err(|_| io::Error::from(io::ErrorKind::NotFound))?;
+        let &index = self.entries.get(&name).ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
+        let idle = self.archives.lock().unwrap().pop();
+        let mut archive = match idle {
+            Some(archive) => archive,
+            None => ZipArchive::new(File::open(&self.path)?)?,
+        };
+        let mut data = Vec::new();
+        {
+            let mut file = archive.by_index(index)?;
+            data.reserve(file.size() as usize);
+            file.read_to_end(&mut data)?;
+        }
+        self.archives.lock().unwrap().push(archive);
+        Ok(data)
+    }
+
+    fn list(&self, directory: &Path) -> Vec<PathBuf> {
+        list_names(self.entries.keys(), directory)
+            .map(|name| self.path.join(name))
+            .collect()
+    }
+}
+
+/// The normalized names in `names` of files directly in `directory`.
+fn list_names<'a, I: Iterator<Item = &'a String>>(names: I, directory: &Path) -> impl Iterator<Item = &'a String> {
+    let mut prefix = normalize(directory);
+    if !prefix.is_empty() && !prefix.ends_with('/') {
+        prefix.push('/');
+    }
+    names.filter(move |name| name.strip_prefix(&prefix).is_some_and(|rest| !rest.contains('/')))
+}
+
+/// Files held in memory, for tests and for generated files.
+#[derive(Debug, Default)]
+pub struct MemorySource {
+    files: HashMap<String, Vec<u8>>,
+}
+
+impl MemorySource {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds or replaces a file, e.g. `insert("parts/3005.dat", text)`.
+    pub fn insert<T: Into<Vec<u8>>>(&mut self, name: &str, contents: T) {
+        self.files.insert(normalize(Path::new(name)), contents.into());
+    }
+}
+
+impl LibrarySource for MemorySource {
+    fn find(&self, relative: &Path) -> Option<PathBuf> {
+        let name = normalize(relative);
+        self.files.get(&name).map(|_| PathBuf::from(name))
+    }
+
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        self.files
+            .get(&normalize(path))
+            .cloned()
+            .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))
+    }
+
+    fn list(&self, directory: &Path) -> Vec<PathBuf> {
+        list_names(self.files.keys(), directory).map(PathBuf::from).collect()
+    }
+}
+
+/// Where `Parser` looks for subfiles.
+pub struct LibraryConfig {
+    /// Library sources in search order, e.g. the official library, its
+    /// `Unofficial` folder and any user folders.
+    pub sources: Vec<Box<dyn LibrarySource>>,
+    /// Whether files are also looked for next to the file that references
+    /// them, after the library sources.
+    pub search_relative: bool,
+    /// Files named by path rather than from the library: models passed on
+    /// the command line and files next to the one referencing them.
+    local: DirectorySource,
+}
+
+impl LibraryConfig {
+    /// A library with no sources, which only finds files by path.
+    pub fn empty() -> Self {
+        Self {
+            sources: Vec::new(),
+            search_relative: true,
+            local: DirectorySource::new(""),
+        }
+    }
+
+    /// The library at `root`: a zip archive such as complete.zip, or a folder
+    /// followed by its `Unofficial` folder if there is one. Fails if `root`
+    /// is an archive that can't be opened.
+    pub fn new<P: AsRef<Path>>(root: P) -> io::Result<Self> {
+        let root = root.as_ref();
+        let mut config = Self::empty();
+        if root.is_file() {
+            config.add_source(ZipSource::open(root)?);
+            return Ok(config);
+        }
+        let directory = DirectorySource::new(root);
+        let unofficial = directory.resolve(Path::new("unofficial")).filter(|path| path.is_dir());
+        config.add_source(directory);
+        if let Some(unofficial) = unofficial {
+            config.add_source(DirectorySource::new(unofficial));
+        }
+        Ok(config)
+    }
+
+    /// The library in the `LDRAWDIR` environment variable, if it's set.
+    pub fn from_env() -> Option<io::Result<Self>> {
+        env::var_os("LDRAWDIR").map(Self::new)
+    }
+
+    /// Adds a source to search after the existing ones.
+    pub fn add_source<S: LibrarySource + 'static>(&mut self, source: S) {
+        self.sources.push(Box::new(source));
+    }
+
+    /// Adds a folder to search after the existing sources.
+    pub fn add_root<P: Into<PathBuf>>(&mut self, root: P) {
+        self.add_source(DirectorySource::new(root));
+    }
+
+    /// Finds a file in the library sources, then next to `referenced_from`,
+    /// then by its path, and reads it. Fails with `NotFound` if it's in
+    /// none of them.
+    pub fn open(&self, filename: &str, referenced_from: Option<&Path>) -> io::Result<(PathBuf, Vec<u8>)> {
+        let (source, path) = self.locate(filename, referenced_from).ok_or(io::ErrorKind::NotFound)?;
+        let data = source.read(&path)?;
+        Ok((path, data))
+    }
+
+    /// The path `open` would read a file from, without reading it.
+    pub fn find(&self, filename: &str, referenced_from: Option<&Path>) -> Option<PathBuf> {
+        self.locate(filename, referenced_from).map(|(_, path)| path)
+    }
+
+    /// The path `open` would read a file from and its stamp, if it's found
+    /// in a source on disk.
+    pub fn stamp(&self, filename: &str, referenced_from: Option<&Path>) -> Option<(PathBuf, Stamp)> {
+        let (source, path) = self.locate(filename, referenced_from)?;
+        let stamp = source.stamp(&path)?;
+        Some((path, stamp))
+    }
+
+    fn locate(&self, filename: &str, referenced_from: Option<&Path>) -> Option<(&dyn LibrarySource, PathBuf)> {
+        for source in &self.sources {
+            for subdirectory in SUBDIRECTORIES {
+                if let Some(path) = source.find(&Path::new(subdirectory).join(filename)) {
+                    return Some((source.as_ref(), path));
+                }
+            }
+        }
+        let mut candidates = Vec::new();
+        if self.search_relative {
+            if let Some(parent) = referenced_from.and_then(Path::parent) {
+                candidates.push(parent.join(filename));
+            }
+        }
+        candidates.push(filename.into());
+        candidates
+            .iter()
+            .find_map(|candidate| self.local.find(candidate))
+            .map(|path| (&self.local as &dyn LibrarySource, path))
+    }
+
+    /// A hash of where files are looked for: the location of each source in
+    /// search order, and whether files are looked for next to the file that
+    /// references them.
+    pub fn fingerprint(&self) -> u64 {
+        let mut bytes = vec![self.search_relative as u8];
+        for source in &self.sources {
+            match source.location() {
+                Some(path) => bytes.extend(path.to_string_lossy().bytes()),
+                None => bytes.push(1),
+            }
+            bytes.push(0);
+        }
+        fnv1a(bytes)
+    }
+
+    /// The first LDConfig.ldr in the sources.
+    pub fn open_ldconfig(&self) -> io::Result<(PathBuf, Vec<u8>)> {
+        for source in &self.sources {
+            if let Some(path) = source.find(Path::new("LDConfig.ldr")) {
+                return Ok((path.clone(), source.read(&path)?));
+            }
+        }
+        Err(io::ErrorKind::NotFound.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rayon::prelude::*;
+    use std::io::Write;
+    use zip::write::FileOptions;
+    use zip::ZipWriter;
+
+    /// An empty folder for one test's files.
+    fn temp_dir(name: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("ldraw-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        path
+    }
+
+    fn write(root: &Path, name: &str, contents: &str) {
+        let path = root.join(name);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, contents).unwrap();
+    }
+
+    fn read(config: &LibraryConfig, filename: &str, referenced_from: Option<&Path>) -> Option<String> {
+        let (_, data) = config.open(filename, referenced_from).ok()?;
+        Some(String::from_utf8(data).unwrap())
+    }
+
+    #[test]
+    fn zip_sources_are_read_from_the_ldconfig_folder() {
+        let path = temp_dir("zip").join("complete.zip");
+        let mut zip = ZipWriter::new(File::create(&path).unwrap());
+        zip.add_directory("ldraw/parts/", FileOptions::default()).unwrap();
+        for (name, contents) in [
+            ("ldraw/LDConfig.ldr", "0 LDraw.org Configuration File\n"),
+            ("ldraw/parts/3001.DAT", "0 Brick  2 x  4\n"),
+            ("ldraw/parts/s/3001s01.dat", "0 ~Brick  2 x  4 without Front Face\n"),
+            ("ldraw/p/4-4disc.dat", "0 Disc 1.0\n"),
+            ("readme.txt", "Not part of the library\n"),
+        ] {
+            zip.start_file(name, FileOptions::default()).unwrap();
+            zip.write_all(contents.as_bytes()).unwrap();
+        }
+        zip.finish().unwrap();
+
+        let source = ZipSource::open(&path).unwrap();
+        assert_eq!(source.len(), 4);
+        assert_eq!(source.location(), Some(path.as_path()));
+        let found = source.find(Path::new("PARTS\\3001.dat")).unwrap();
+        assert_eq!(found, path.join("parts/3001.dat"));
+        assert_eq!(source.read(&found).unwrap(), b"0 Brick  2 x  4\n");
+        assert!(source.find(Path::new("readme.txt")).is_none());
+        assert_eq!(source.read(Path::new("parts/3001.dat")).unwrap_err().kind(), io::ErrorKind::NotFound);
+        assert_eq!(source.list(Path::new("parts")), [path.join("parts/3001.dat")]);
+
+        let names = ["ldconfig.ldr", "parts/3001.dat", "parts/s/3001s01.dat", "p/4-4disc.dat"];
+        let reads: Vec<usize> = (0..64)
+            .into_par_iter()
+            .map(|i| source.read(&path.join(names[i % names.len()])).unwrap().len())
+            .collect();
+        assert!(reads.iter().all(|&len| len > 0));
+    }
+
+    #[test]
+    fn directory_sources_match_names_case_insensitively() {
+        let root = temp_dir("directory");
+        write(&root, "PARTS/S/3001s01.Dat", "0 ~Brick  2 x  4 without Front Face\n");
+        write(&root, "PARTS/3001.dat", "0 Brick  2 x  4\n");
+
+        let mut source = DirectorySource::new(&root);
+        assert_eq!(source.find(Path::new("parts/s/3001S01.DAT")), Some(root.join("PARTS/S/3001s01.Dat")));
+        assert_eq!(source.find(Path::new("parts/3001.dat")), Some(root.join("PARTS/3001.dat")));
+        assert_eq!(source.find(Path::new("parts/s")), None);
+        assert_eq!(source.list(Path::new("parts")), [root.join("PARTS/3001.dat")]);
+
+        source.case_insensitive = false;
+        assert_eq!(source.find(Path::new("parts/3001.dat")), None);
+        assert_eq!(source.find(Path::new("PARTS/3001.dat")), Some(root.join("PARTS/3001.dat")));
+    }
+
+    #[test]
+    fn files_are_looked_for_in_the_sources_then_next_to_the_referencing_file() {
+        let root = temp_dir("locate");
+        write(&root, "model.ldr", "");
+        write(&root, "3001.dat", "next to the model");
+        write(&root, "local.dat", "next to the model");
+
+        let mut first = MemorySource::new();
+        first.insert("parts/3001.dat", "first, in parts");
+        first.insert("p/3001.dat", "first, in p");
+        let mut second = MemorySource::new();
+        second.insert("3001.dat", "second, at the root");
+        second.insert("p/4-4disc.dat", "second, in p");
+        let mut config = LibraryConfig::empty();
+        config.add_source(first);
+        config.add_source(second);
+
+        let model = root.join("model.ldr");
+        assert_eq!(read(&config, "3001.dat", Some(&model)).unwrap(), "first, in parts");
+        assert_eq!(read(&config, "4-4disc.dat", None).unwrap(), "second, in p");
+        assert_eq!(read(&config, "local.dat", Some(&model)).unwrap(), "next to the model");
+        assert_eq!(config.find("local.dat", Some(&model)), Some(root.join("local.dat")));
+        let path = root.join("local.dat");
+        assert_eq!(read(&config, &path.to_string_lossy(), None).unwrap(), "next to the model");
+
+        config.search_relative = false;
+        assert_eq!(read(&config, "local.dat", Some(&model)), None);
+    }
+}
@@ -0,0 +1,143 @@
+use super::normals::{smooth_normals, Welder};
+use super::parser::{Geometry, LdrawColor};
+use cgmath::{Deg, Point3, Vector3};
+use std::collections::HashMap;
+
+/// Triangles that share their vertices, ready to upload or export. Corners
+/// are the same vertex when their positions weld together and they have the
+/// same normal and color.
+#[derive(Clone, Debug, Default)]
+pub struct Mesh {
+    pub positions: Vec<Point3<f32>>,
+    pub normals: Vec<Vector3<f32>>,
+    pub colors: Vec<[f32; 4]>,
+    /// Three per triangle.
+    pub indices: Vec<u32>,
+    /// The number of indices at the start of `indices` that belong to
+    /// BFC-certified polygons and can be back-face culled.
+    pub culled_index_count: usize,
+}
+
+impl Mesh {
+    /// The polygons of `geometry` with smooth normals, as `smooth_normals`
+    /// gives them. Polygons that use the main or edge color, which only
+    /// happens when a part is loaded on its own, are drawn in `main_color`.
+    pub fn new(geometry: &Geometry, crease_angle: Deg<f32>, main_color: [f32; 4]) -> Self {
+        let normals = smooth_normals(geometry, crease_angle);
+        let mut polygons: Vec<_> = geometry.polygons.iter().zip(&normals).collect();
+        polygons.sort_by_key(|(polygon, _)| !polygon.cull);
+
+        let mut mesh = Self::default();
+        let mut welder = Welder::new();
+        let mut vertices: HashMap<(u32, [u32; 3], [u32; 4]), u32> = HashMap::new();
+        for (polygon, normals) in polygons {
+            let color = match polygon.color {
+                LdrawColor::RGBA(r, g, b, a) => [r, g, b, a],
+                _ => main_color,
+            };
+            let corners: Vec<u32> = polygon
+                .points
+                .iter()
+                .zip(normals)
+                .map(|(&point, &normal)| {
+                    let position = welder.weld(point);
+                    let key = (position, [normal.x.to_bits(), normal.y.to_bits(), normal.z.to_bits()], color.map(f32::to_bits));
+                    *vertices.entry(key).or_insert_with(|| {
+                        mesh.positions.push(welder.positions[position as usize]);
+                        mesh.normals.push(normal);
+                        mesh.colors.push(color);
+                        mesh.positions.len() as u32 - 1
+                    })
+                })
+                .collect();
+            // Polygons are triangles, but fans cover anything convex.
+            for i in 1..corners.len().saturating_sub(1) {
+                mesh.indices.extend_from_slice(&[corners[0], corners[i], corners[i + 1]]);
+            }
+            if polygon.cull {
+                mesh.culled_index_count = mesh.indices.len();
+            }
+        }
+        mesh
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::normals::DEFAULT_CREASE_ANGLE;
+    use crate::parser::Polygon;
+
+    const RED: [f32; 4] = [1.0, 0.0, 0.0, 1.0];
+    const GRAY: [f32; 4] = [0.5, 0.5, 0.5, 1.0];
+
+    fn polygon(points: &[[f32; 3]], color: LdrawColor, cull: bool) -> Polygon {
+        Polygon {
+            points: points.iter().map(|&p| p.into()).collect(),
+            color,
+            cull,
+        }
+    }
+
+    fn red() -> LdrawColor {
+        let [r, g, b, a] = RED;
+        LdrawColor::RGBA(r, g, b, a)
+    }
+
+    /// The triangles of `mesh` as positions, in order.
+    fn triangles(mesh: &Mesh) -> Vec<[Point3<f32>; 3]> {
+        mesh.indices
+            .chunks(3)
+            .map(|t| [mesh.positions[t[0] as usize], mesh.positions[t[1] as usize], mesh.positions[t[2] as usize]])
+            .collect()
+    }
+
+    #[test]
+    fn culled_polygons_come_first() {
+        let mut geometry = Geometry::default();
+        geometry.polygons.push(polygon(&[[0.0, 0.0, 0.0], [1.0, 0.0, 0.0], [0.0, 1.0, 0.0]], red(), false));
+        geometry.polygons.push(polygon(
+            &[[0.0, 0.0, 5.0], [1.0, 0.0, 5.0], [1.0, 1.0, 5.0], [0.0, 1.0, 5.0]],
+            red(),
+            true,
+        ));
+        geometry.polygons.push(polygon(&[[0.0, 0.0, 9.0], [1.0, 0.0, 9.0], [0.0, 1.0, 9.0]], red(), false));
+        geometry.polygons.push(polygon(&[[0.0, 0.0, 7.0], [1.0, 0.0, 7.0], [0.0, 1.0, 7.0]], red(), true));
+
+        let mesh = Mesh::new(&geometry, DEFAULT_CREASE_ANGLE, GRAY);
+        assert_eq!(mesh.indices.len(), 15);
+        assert_eq!(mesh.culled_index_count, 9);
+        // The quad is a fan, and polygons otherwise keep their order.
+        let z: Vec<f32> = triangles(&mesh).iter().map(|t| t[0].z).collect();
+        assert_eq!(z, [5.0, 5.0, 7.0, 0.0, 9.0]);
+
+        geometry.polygons.retain(|polygon| !polygon.cull);
+        assert_eq!(Mesh::new(&geometry, DEFAULT_CREASE_ANGLE, GRAY).culled_index_count, 0);
+    }
+
+    #[test]
+    fn corners_share_vertices_with_the_same_position_normal_and_color() {
+        // Two coplanar triangles that share an edge, one a hair off.
+        let mut geometry = Geometry::default();
+        geometry.polygons.push(polygon(&[[0.0, 0.0, 0.0], [1.0, 0.0, 0.0], [0.0, 1.0, 0.0]], red(), true));
+        geometry.polygons.push(polygon(&[[1.0, 0.0, 0.0], [1.0, 1.0, 0.0], [0.0, 1.001, 0.0]], red(), true));
+        let mesh = Mesh::new(&geometry, DEFAULT_CREASE_ANGLE, GRAY);
+        assert_eq!(mesh.positions.len(), 4);
+        assert_eq!(mesh.indices, [0, 1, 2, 1, 3, 2]);
+        assert_eq!(mesh.positions[2], Point3::new(0.0, 1.0, 0.0));
+
+        // A different color splits the shared corners.
+        geometry.polygons[1].color = LdrawColor::Main;
+        let mesh = Mesh::new(&geometry, DEFAULT_CREASE_ANGLE, GRAY);
+        assert_eq!(mesh.positions.len(), 6);
+        assert_eq!(&mesh.colors[3..], [GRAY; 3]);
+
+        // So does a crease.
+        geometry.polygons[1].color = red();
+        geometry.polygons[1].points[1].z = 3.0;
+        let mesh = Mesh::new(&geometry, DEFAULT_CREASE_ANGLE, GRAY);
+        assert_eq!(mesh.positions.len(), 6);
+        let mesh = Mesh::new(&geometry, Deg(90.0), GRAY);
+        assert_eq!(mesh.positions.len(), 4);
+    }
+}
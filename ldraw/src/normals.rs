@@ -0,0 +1,281 @@
+use super::parser::{norm, Geometry};
+use cgmath::prelude::*;
+use cgmath::{Deg, Point3, Vector3};
+use std::collections::{HashMap, HashSet};
+
+/// Points closer together than this, in LDU, are the same vertex. Real
+/// detail is never this small, but points that should meet often miss each
+/// other by about this much once they've been through a few transforms.
+pub const WELD_DISTANCE: f32 = 0.01;
+
+/// Faces that meet at a sharper angle than this are given a hard edge even
+/// if there's no edge line between them.
+pub const DEFAULT_CREASE_ANGLE: Deg<f32> = Deg(60.0);
+
+/// Merges points within `WELD_DISTANCE` of each other into one vertex.
+#[derive(Default)]
+pub struct Welder {
+    /// The vertices in each cell of a grid with `WELD_DISTANCE` sides, so
+    /// that only the neighboring cells need searching.
+    cells: HashMap<[i64; 3], Vec<u32>>,
+    pub positions: Vec<Point3<f32>>,
+}
+
+impl Welder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn cell(point: Point3<f32>) -> [i64; 3] {
+        let cell = |x: f32| (x / WELD_DISTANCE).floor() as i64;
+        [cell(point.x), cell(point.y), cell(point.z)]
+    }
+
+    /// The index of the vertex at `point`, adding one if there isn't one
+    /// close enough.
+    pub fn weld(&mut self, point: Point3<f32>) -> u32 {
+        let [x, y, z] = Self::cell(point);
+        for dx in -1..=1 {
+            for dy in -1..=1 {
+                for dz in -1..=1 {
+                    for &i in self.cells.get(&[x + dx, y + dy, z + dz]).into_iter().flatten() {
+                        if self.positions[i as usize].distance2(point) <= WELD_DISTANCE * WELD_DISTANCE {
+                            return i;
+                        }
+                    }
+                }
+            }
+        }
+        let i = self.positions.len() as u32;
+        self.positions.push(point);
+        self.cells.entry([x, y, z]).or_default().push(i);
+        i
+    }
+}
+
+/// Finds which set each item is in, for grouping polygon corners.
+struct Sets {
+    parents: Vec<usize>,
+}
+
+impl Sets {
+    fn new(count: usize) -> Self {
+        Self {
+            parents: (0..count).collect(),
+        }
+    }
+
+    fn find(&mut self, mut i: usize) -> usize {
+        while self.parents[i] != i {
+            self.parents[i] = self.parents[self.parents[i]];
+            i = self.parents[i];
+        }
+        i
+    }
+
+    fn join(&mut self, a: usize, b: usize) {
+        let (a, b) = (self.find(a), self.find(b));
+        self.parents[a] = b;
+    }
+}
+
+fn edge(a: u32, b: u32) -> (u32, u32) {
+    if a < b {
+        (a, b)
+    } else {
+        (b, a)
+    }
+}
+
+/// A normal for every point of every polygon in `geometry`, in the same
+/// order. Polygons that share an edge are shaded smoothly across it, unless
+/// there's an edge line along it or they meet at more than `crease_angle`.
+/// Each normal is the average of the normals of the polygons it's smooth
+/// across, weighted by their angles at the point.
+pub fn smooth_normals(geometry: &Geometry, crease_angle: Deg<f32>) -> Vec<Vec<Vector3<f32>>> {
+    let polygons = &geometry.polygons;
+    let mut welder = Welder::new();
+    let vertices: Vec<Vec<u32>> = polygons
+        .iter()
+        .map(|polygon| polygon.points.iter().map(|&p| welder.weld(p)).collect())
+        .collect();
+    let creases: HashSet<(u32, u32)> = geometry
+        .edges
+        .iter()
+        .map(|e| edge(welder.weld(e.points[0]), welder.weld(e.points[1])))
+        .collect();
+    let normals: Vec<Vector3<f32>> = polygons
+        .iter()
+        .map(|polygon| {
+            let n = norm(polygon);
+            if n.magnitude2() > 0.0 {
+                n.normalize()
+            } else {
+                n
+            }
+        })
+        .collect();
+
+    // Corners are numbered in order through all the polygons.
+    let mut first_corner = Vec::with_capacity(polygons.len());
+    let mut corners = 0;
+    for polygon in &vertices {
+        first_corner.push(corners);
+        corners += polygon.len();
+    }
+    let corner = |polygon: usize, vertex: u32| first_corner[polygon] + vertices[polygon].iter().position(|&v| v == vertex).unwrap();
+
+    let mut edges: HashMap<(u32, u32), Vec<usize>> = HashMap::new();
+    for (i, polygon) in vertices.iter().enumerate() {
+        for (j, &a) in polygon.iter().enumerate() {
+            let b = polygon[(j + 1) % polygon.len()];
+            if a != b {
+                edges.entry(edge(a, b)).or_default().push(i);
+            }
+        }
+    }
+
+    let min_cos = cgmath::Rad::from(crease_angle).0.cos();
+    let mut sets = Sets::new(corners);
+    for (&(a, b), faces) in &edges {
+        if creases.contains(&(a, b)) {
+            continue;
+        }
+        for (k, &f) in faces.iter().enumerate() {
+            for &g in &faces[k + 1..] {
+                if normals[f].magnitude2() > 0.0 && normals[g].magnitude2() > 0.0 && normals[f].dot(normals[g]) >= min_cos {
+                    sets.join(corner(f, a), corner(g, a));
+                    sets.join(corner(f, b), corner(g, b));
+                }
+            }
+        }
+    }
+
+    let mut sums = vec![Vector3::zero(); corners];
+    for (i, polygon) in polygons.iter().enumerate() {
+        let points = &polygon.points;
+        for (j, &p) in points.iter().enumerate() {
+            let u = points[(j + 1) % points.len()] - p;
+            let v = points[(j + points.len() - 1) % points.len()] - p;
+            let weight = if u.magnitude2() > 0.0 && v.magnitude2() > 0.0 { u.angle(v).0 } else { 0.0 };
+            let set = sets.find(first_corner[i] + j);
+            sums[set] += normals[i] * weight;
+        }
+    }
+    polygons
+        .iter()
+        .enumerate()
+        .map(|(i, polygon)| {
+            (0..polygon.points.len())
+                .map(|j| {
+                    let sum = sums[sets.find(first_corner[i] + j)];
+                    if sum.magnitude2() > 0.0 {
+                        sum.normalize()
+                    } else {
+                        normals[i]
+                    }
+                })
+                .collect()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::colors::LdrawColor;
+    use crate::parser::{Edge, Polygon};
+
+    /// Two triangles sharing the edge from the origin along z, folded so that
+    /// their faces meet at `angle` from flat. The second one's corners are
+    /// its end of the shared edge, then the origin, then its own corner.
+    fn folded(angle: Deg<f32>, edge_line: bool) -> Geometry {
+        let (sin, cos) = cgmath::Rad::from(angle).0.sin_cos();
+        let triangle = |points: [Point3<f32>; 3]| Polygon {
+            points: points.to_vec(),
+            color: LdrawColor::Main,
+            cull: true,
+        };
+        let mut geometry = Geometry::default();
+        geometry.polygons.push(triangle([
+            Point3::new(0.0, 0.0, 0.0),
+            Point3::new(0.0, 0.0, 10.0),
+            Point3::new(10.0, 0.0, 5.0),
+        ]));
+        geometry.polygons.push(triangle([
+            Point3::new(0.0, 0.0, 10.0),
+            Point3::new(0.0, 0.0, 0.0),
+            Point3::new(-10.0 * cos, 10.0 * sin, 5.0),
+        ]));
+        if edge_line {
+            geometry.edges.push(Edge {
+                points: [Point3::new(0.0, 0.0, 0.0), Point3::new(0.0, 0.0, 10.0)],
+                color: LdrawColor::Complement,
+            });
+        }
+        geometry
+    }
+
+    fn same(a: Vector3<f32>, b: Vector3<f32>) -> bool {
+        (a - b).magnitude() < 1e-5
+    }
+
+    /// Whether the triangles from `folded` are shaded smoothly across the
+    /// shared edge. Otherwise each corner has its face's normal.
+    fn smooth(normals: &[Vec<Vector3<f32>>]) -> bool {
+        let (a, b) = (&normals[0], &normals[1]);
+        if same(a[0], b[1]) && same(a[1], b[0]) {
+            assert!(!same(a[0], a[2]) && !same(b[0], b[2]));
+            true
+        } else {
+            assert!(same(a[0], a[1]) && same(a[0], a[2]));
+            assert!(same(b[0], b[1]) && same(b[0], b[2]));
+            false
+        }
+    }
+
+    #[test]
+    fn shallow_folds_are_smooth_and_sharp_ones_are_creased() {
+        let normals = smooth_normals(&folded(Deg(10.0), false), DEFAULT_CREASE_ANGLE);
+        assert!(smooth(&normals));
+        // Halfway between the two faces.
+        assert!((normals[0][0].angle(normals[0][2]).0 - cgmath::Rad::from(Deg(5.0f32)).0).abs() < 1e-4);
+
+        assert!(!smooth(&smooth_normals(&folded(Deg(90.0), false), DEFAULT_CREASE_ANGLE)));
+        assert!(smooth(&smooth_normals(&folded(Deg(90.0), false), Deg(95.0))));
+        assert!(smooth(&smooth_normals(&folded(Deg(59.0), false), DEFAULT_CREASE_ANGLE)));
+        assert!(!smooth(&smooth_normals(&folded(Deg(61.0), false), DEFAULT_CREASE_ANGLE)));
+    }
+
+    #[test]
+    fn edge_lines_are_creases() {
+        assert!(!smooth(&smooth_normals(&folded(Deg(10.0), true), DEFAULT_CREASE_ANGLE)));
+        assert!(!smooth(&smooth_normals(&folded(Deg(10.0), true), Deg(180.0))));
+    }
+
+    #[test]
+    fn points_that_nearly_meet_are_welded() {
+        let mut geometry = folded(Deg(10.0), true);
+        for point in &mut geometry.polygons[1].points[..2] {
+            point.x += WELD_DISTANCE / 2.0;
+        }
+        geometry.edges[0].points[1].y -= WELD_DISTANCE / 2.0;
+        assert!(!smooth(&smooth_normals(&geometry, DEFAULT_CREASE_ANGLE)));
+        geometry.edges.clear();
+        assert!(smooth(&smooth_normals(&geometry, DEFAULT_CREASE_ANGLE)));
+
+        for point in &mut geometry.polygons[1].points[..2] {
+            point.x += WELD_DISTANCE * 2.0;
+        }
+        assert!(!smooth(&smooth_normals(&geometry, DEFAULT_CREASE_ANGLE)));
+    }
+
+    #[test]
+    fn faces_wound_the_other_way_are_not_smoothed_together() {
+        // The second face points the other way, so the fold is 170°.
+        let mut geometry = folded(Deg(10.0), false);
+        geometry.polygons[1].points.swap(0, 1);
+        assert!(!smooth(&smooth_normals(&geometry, DEFAULT_CREASE_ANGLE)));
+        assert!(smooth(&smooth_normals(&geometry, Deg(175.0))));
+    }
+}
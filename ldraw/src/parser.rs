@@ -0,0 +1,2088 @@
+use cgmath::prelude::*;
+use cgmath::{Matrix4, Point3, Vector2, Vector3};
+use std::error::Error;
+use std::fmt;
+use std::fs::OpenOptions;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use std::collections::HashMap;
+use std::sync::Arc;
+use rayon::prelude::*;
+
+use super::cache::{fnv1a, Dependency, DiskCache};
+use super::colors::ColorTable;
+use super::document;
+use super::library::LibraryConfig;
+use super::mesh::Mesh;
+use super::normals::DEFAULT_CREASE_ANGLE;
+pub use super::colors::LdrawColor;
+pub use super::document::LdrawDocument;
+
+#[derive(Clone, Debug)]
+pub struct Polygon {
+    pub points: Vec<Point3<f32>>,
+    pub color: LdrawColor,
+    /// Whether the polygon comes from BFC-certified geometry with clipping
+    /// on, so its back face can be culled. Otherwise it must be two-sided.
+    pub cull: bool,
+}
+
+/// A type-2 line, drawn as an outline along the edges of a part.
+#[derive(Clone, Debug)]
+pub struct Edge {
+    pub points: [Point3<f32>; 2],
+    pub color: LdrawColor,
+}
+
+/// A type-5 optional line. It's only drawn when both control points are on
+/// the same side of it on screen, which is what makes the outlines of curved
+/// surfaces like studs and cylinders show up.
+#[derive(Clone, Debug)]
+pub struct ConditionalEdge {
+    pub points: [Point3<f32>; 2],
+    pub controls: [Point3<f32>; 2],
+    pub color: LdrawColor,
+}
+
+impl ConditionalEdge {
+    /// Whether the line should be drawn when viewed through `transform`,
+    /// which takes model coordinates to clip space (i.e. proj * view * world).
+    pub fn is_visible(&self, transform: &Matrix4<f32>) -> bool {
+        let project = |p: Point3<f32>| {
+            let clip = transform * p.to_homogeneous();
+            Vector2::new(clip.x / clip.w, clip.y / clip.w)
+        };
+        let a = project(self.points[0]);
+        let d = project(self.points[1]) - a;
+        let side = |p: Point3<f32>| {
+            let c = project(p) - a;
+            d.x * c.y - d.y * c.x
+        };
+        side(self.controls[0]) * side(self.controls[1]) >= 0.0
+    }
+}
+
+/// A type-1 line as written in the file, before the subfile is resolved.
+#[derive(Clone, Debug)]
+pub struct PartReference {
+    pub name: String,
+    pub color: LdrawColor,
+    pub edge_color: LdrawColor,
+    pub transform: Matrix4<f32>,
+    /// Whether the subfile's winding is reversed, by `0 BFC INVERTNEXT` or
+    /// by a mirroring transform.
+    pub inverted: bool,
+    /// Whether the subfile's polygons may be culled, which they can't be if
+    /// this file isn't certified or has clipping off.
+    pub clip: bool,
+}
+
+/// The end of a build step (`0 STEP` or `0 ROTSTEP`). Geometry is stored in
+/// file order, so everything drawn up to the end of the step is the first
+/// `polygons` polygons, `edges` edges and `conditional_edges` optional lines.
+#[derive(Clone, Debug, Default)]
+pub struct Step {
+    pub polygons: usize,
+    pub edges: usize,
+    pub conditional_edges: usize,
+    /// The subfiles referenced during this step.
+    pub parts: Vec<PartReference>,
+}
+
+/// Everything drawn by a file. This is either just the file's own lines, as
+/// in the meshes of a `Scene`, or those plus all of its resolved subfiles.
+#[derive(Clone, Debug, Default)]
+pub struct Geometry {
+    pub polygons: Vec<Polygon>,
+    pub edges: Vec<Edge>,
+    pub conditional_edges: Vec<ConditionalEdge>,
+    /// The file's own build steps. Steps in subfiles aren't included, since
+    /// a subfile is added all at once in the step that references it.
+    pub steps: Vec<Step>,
+}
+
+/// A file placed in a model: the model itself, or one of the subfiles under
+/// it, all the way down to primitives.
+#[derive(Clone, Debug)]
+pub struct PartInstance {
+    /// The file's key in `Scene::meshes`, which is its resolved path.
+    pub file: String,
+    /// What Main (16) and Complement (24) stand for in the file's mesh, after
+    /// resolving them against the parents.
+    pub color: LdrawColor,
+    pub edge_color: LdrawColor,
+    /// The placement relative to the parent instance.
+    pub transform: Matrix4<f32>,
+    /// Whether the mesh's winding has to be reversed.
+    pub inverted: bool,
+    /// Whether the mesh's cullable polygons can be culled here.
+    pub clip: bool,
+    pub children: Vec<PartInstance>,
+}
+
+impl PartInstance {
+    /// Calls `f` for this instance and everything under it, parents first,
+    /// with each one's transform relative to this instance's parent.
+    pub fn visit<F: FnMut(&PartInstance, &Matrix4<f32>)>(&self, f: &mut F) {
+        self.visit_from(&Matrix4::identity(), f);
+    }
+
+    fn visit_from<F: FnMut(&PartInstance, &Matrix4<f32>)>(&self, parent: &Matrix4<f32>, f: &mut F) {
+        let transform = parent * self.transform;
+        f(self, &transform);
+        for child in &self.children {
+            child.visit_from(&transform, f);
+        }
+    }
+}
+
+/// A model as a tree of instances, with each file's own lines parsed once
+/// and shared by every instance of it.
+#[derive(Clone, Debug)]
+pub struct Scene {
+    pub root: PartInstance,
+    pub meshes: HashMap<String, Arc<Geometry>>,
+}
+
+impl Geometry {
+    /// Ends the current step, if anything has been added since the last one.
+    fn end_step(&mut self, parts: &mut Vec<PartReference>) {
+        let (polygons, edges, conditional_edges) = match self.steps.last() {
+            Some(s) => (s.polygons, s.edges, s.conditional_edges),
+            None => (0, 0, 0),
+        };
+        if parts.is_empty()
+            && self.polygons.len() == polygons
+            && self.edges.len() == edges
+            && self.conditional_edges.len() == conditional_edges
+        {
+            return;
+        }
+        self.steps.push(Step {
+            polygons: self.polygons.len(),
+            edges: self.edges.len(),
+            conditional_edges: self.conditional_edges.len(),
+            parts: std::mem::take(parts),
+        });
+    }
+
+    /// Everything drawn by the end of `step` (counting from 0). Steps past
+    /// the last one give the whole model.
+    pub fn up_to_step(&self, step: usize) -> Geometry {
+        let end = match self.steps.get(step) {
+            Some(end) => end,
+            None => return self.clone(),
+        };
+        Geometry {
+            polygons: self.polygons[..end.polygons].to_vec(),
+            edges: self.edges[..end.edges].to_vec(),
+            conditional_edges: self.conditional_edges[..end.conditional_edges].to_vec(),
+            steps: self.steps[..=step].to_vec(),
+        }
+    }
+
+    /// The parts that are added in `step` (counting from 0).
+    pub fn parts_in_step(&self, step: usize) -> &[PartReference] {
+        match self.steps.get(step) {
+            Some(step) => &step.parts,
+            None => &[],
+        }
+    }
+
+    /// The optional lines that should be drawn when viewed through
+    /// `transform`, for exporters that need to bake a single view.
+    pub fn visible_conditional_edges<'a>(&'a self, transform: &'a Matrix4<f32>) -> impl Iterator<Item = &'a ConditionalEdge> {
+        self.conditional_edges.iter().filter(move |e| e.is_visible(transform))
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ParseErrorKind {
+    /// The file wasn't found in any of the search paths.
+    FileNotFound,
+    /// The file was found but couldn't be read.
+    Io(String),
+    /// A token that should be a number couldn't be parsed as one.
+    InvalidNumber,
+    /// The line ended before all of the tokens its line type needs.
+    MissingTokens { expected: usize, found: usize },
+    /// A meta command that isn't valid, or isn't valid where it appears.
+    InvalidCommand(String),
+    /// A color code that isn't in the color table.
+    UnknownColor,
+    /// A subfile that references itself, directly or through other files.
+    /// Holds the chain of files from the first one in the cycle back to it.
+    Cycle(Vec<String>),
+    /// Subfiles nested deeper than the parser's maximum depth.
+    TooDeep(usize),
+    /// The file isn't valid UTF-8. It's still read, with the invalid bytes
+    /// taken as Latin-1.
+    InvalidUtf8,
+    /// A quad that isn't convex and planar, as the spec requires. It's still
+    /// drawn, as well as it can be.
+    InvalidQuad(String),
+}
+
+/// An error in an LDraw file, along with where it happened.
+#[derive(Clone, Debug)]
+pub struct ParseError {
+    pub path: PathBuf,
+    /// 1-based line number, or 0 if the error isn't tied to a line.
+    pub line: usize,
+    /// 1-based column of the offending token, or 0 if there isn't one.
+    pub column: usize,
+    pub token: Option<String>,
+    pub kind: ParseErrorKind,
+}
+
+impl ParseError {
+    fn new(path: &Path, kind: ParseErrorKind) -> Self {
+        Self {
+            path: path.into(),
+            line: 0,
+            column: 0,
+            token: None,
+            kind,
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.path.display())?;
+        if self.line > 0 {
+            write!(f, ":{}", self.line)?;
+        }
+        if self.column > 0 {
+            write!(f, ":{}", self.column)?;
+        }
+        match &self.kind {
+            ParseErrorKind::FileNotFound => write!(f, ": couldn't find file")?,
+            ParseErrorKind::Io(e) => write!(f, ": couldn't read file: {}", e)?,
+            ParseErrorKind::InvalidNumber => write!(f, ": invalid number")?,
+            ParseErrorKind::MissingTokens { expected, found } => {
+                write!(f, ": expected {} tokens, found {}", expected, found)?
+            }
+            ParseErrorKind::InvalidCommand(reason) => write!(f, ": {}", reason)?,
+            ParseErrorKind::UnknownColor => write!(f, ": unknown color")?,
+            ParseErrorKind::Cycle(chain) => write!(f, ": subfile references itself: {}", chain.join(" -> "))?,
+            ParseErrorKind::TooDeep(depth) => write!(f, ": subfiles nested more than {} deep", depth)?,
+            ParseErrorKind::InvalidUtf8 => write!(f, ": invalid UTF-8, read as Latin-1")?,
+            ParseErrorKind::InvalidQuad(reason) => write!(f, ": {}", reason)?,
+        }
+        if let Some(token) = &self.token {
+            write!(f, " `{}`", token)?;
+        }
+        Ok(())
+    }
+}
+
+impl Error for ParseError {}
+
+/// Non-fatal problems found while loading, such as skipped lines and
+/// missing subparts. They're kept for the caller to show however it likes.
+#[derive(Default)]
+struct Diagnostics {
+    entries: Vec<ParseError>,
+}
+
+impl Diagnostics {
+    fn report(&mut self, error: ParseError) {
+        self.entries.push(error);
+    }
+}
+
+/// Splits a line into whitespace-separated tokens, along with the byte
+/// offset each token starts at.
+fn tokenize(text: &str) -> Vec<(usize, &str)> {
+    let mut tokens = Vec::new();
+    let mut start = None;
+    for (i, c) in text.char_indices() {
+        match (c.is_whitespace(), start) {
+            (true, Some(s)) => {
+                tokens.push((s, &text[s..i]));
+                start = None;
+            }
+            (false, None) => start = Some(i),
+            _ => {}
+        }
+    }
+    if let Some(s) = start {
+        tokens.push((s, &text[s..]));
+    }
+    tokens
+}
+
+/// A single line of an LDraw file, split into tokens.
+struct Line<'a> {
+    path: &'a Path,
+    number: usize,
+    text: &'a str,
+    tokens: Vec<&'a str>,
+    offsets: Vec<usize>,
+}
+
+impl<'a> Line<'a> {
+    fn new(path: &'a Path, number: usize, text: &'a str) -> Self {
+        let (offsets, tokens) = tokenize(text).into_iter().unzip();
+        Self {
+            path,
+            number,
+            text,
+            tokens,
+            offsets,
+        }
+    }
+
+    /// Everything from the token at `index` to the end of the line. Used for
+    /// file names and meta command text, which may contain spaces.
+    fn rest(&self, index: usize) -> &'a str {
+        match self.offsets.get(index) {
+            Some(&offset) => self.text[offset..].trim_end(),
+            None => "",
+        }
+    }
+
+    fn error(&self, index: usize, kind: ParseErrorKind) -> ParseError {
+        let (column, token) = match self.tokens.get(index) {
+            Some(token) => (self.offsets[index] + 1, Some(token.to_string())),
+            None => (0, None),
+        };
+        ParseError {
+            path: self.path.into(),
+            line: self.number,
+            column,
+            token,
+            kind,
+        }
+    }
+
+    fn expect_tokens(&self, expected: usize) -> Result<(), ParseError> {
+        if self.tokens.len() < expected {
+            return Err(self.error(
+                self.tokens.len(),
+                ParseErrorKind::MissingTokens { expected, found: self.tokens.len() },
+            ));
+        }
+        Ok(())
+    }
+
+    fn float(&self, index: usize) -> Result<f32, ParseError> {
+        self.tokens[index]
+            .parse::<f32>()
+            .map_err(|_| self.error(index, ParseErrorKind::InvalidNumber))
+    }
+
+    fn point(&self, index: usize) -> Result<Point3<f32>, ParseError> {
+        Ok(Point3 {
+            x: self.float(index)?,
+            y: self.float(index + 1)?,
+            z: self.float(index + 2)?,
+        })
+    }
+}
+
+pub fn norm(p: &Polygon) -> Vector3<f32> {
+    let u = Vector3 {
+        x: p.points[1].x - p.points[0].x,
+        y: p.points[1].y - p.points[0].y,
+        z: p.points[1].z - p.points[0].z,
+    };
+    let v = Vector3 {
+        x: p.points[2].x - p.points[0].x,
+        y: p.points[2].y - p.points[0].y,
+        z: p.points[2].z - p.points[0].z,
+    };
+    Vector3 {
+        x: (u.y * v.z - u.z * v.y),
+        y: -(u.x * v.z - u.z * v.x),
+        z: (u.x * v.y - u.y * v.x),
+    }
+}
+
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Winding {
+    CW,
+    CCW,
+}
+
+/// Back-face culling state of the file being parsed, following the BFC spec.
+struct Bfc {
+    /// `None` until a `BFC CERTIFY` or `BFC NOCERTIFY` or the first drawing
+    /// command, which makes the file uncertified.
+    certified: Option<bool>,
+    winding: Winding,
+    clip: bool,
+    invert_next: bool,
+}
+
+impl Bfc {
+    fn new() -> Self {
+        Self {
+            certified: None,
+            winding: Winding::CCW,
+            clip: true,
+            invert_next: false,
+        }
+    }
+
+    fn certified(&self) -> bool {
+        self.certified == Some(true)
+    }
+
+    /// Whether geometry drawn right now can be culled.
+    fn cull(&self) -> bool {
+        self.certified() && self.clip
+    }
+
+    /// Called for every drawing command, since a file that draws something
+    /// before certifying itself isn't certified.
+    fn draw(&mut self) {
+        if self.certified.is_none() {
+            self.certified = Some(false);
+        }
+    }
+
+    /// Applies a `0 BFC ...` line.
+    fn apply(&mut self, line: &Line) -> Result<(), ParseError> {
+        let options = &line.tokens[2..];
+        let invalid = |reason: &str| Err(line.error(2, ParseErrorKind::InvalidCommand(reason.into())));
+        match options {
+            ["CERTIFY", winding @ ..] => {
+                if self.certified == Some(false) {
+                    return invalid("BFC CERTIFY after drawing commands or NOCERTIFY");
+                }
+                self.certified = Some(true);
+                self.winding = match winding {
+                    [] | ["CCW"] => Winding::CCW,
+                    ["CW"] => Winding::CW,
+                    _ => return invalid("invalid BFC CERTIFY winding"),
+                };
+            }
+            ["NOCERTIFY"] => {
+                if self.certified == Some(true) {
+                    return invalid("BFC NOCERTIFY in a certified file");
+                }
+                self.certified = Some(false);
+            }
+            ["INVERTNEXT"] => self.invert_next = true,
+            ["NOCLIP"] => self.clip = false,
+            ["CLIP"] => self.clip = true,
+            ["CW"] => self.winding = Winding::CW,
+            ["CCW"] => self.winding = Winding::CCW,
+            ["CLIP", "CW"] | ["CW", "CLIP"] => {
+                self.clip = true;
+                self.winding = Winding::CW;
+            }
+            ["CLIP", "CCW"] | ["CCW", "CLIP"] => {
+                self.clip = true;
+                self.winding = Winding::CCW;
+            }
+            _ => return invalid("unknown BFC command"),
+        }
+        Ok(())
+    }
+}
+
+#[derive(Hash, PartialEq, Debug)]
+pub struct CacheKey {
+    name: String,
+    inverted: bool,
+}
+
+// TODO can probably just derive Eq
+impl Eq for CacheKey {}
+
+fn parse_subfile_ref<'a>(line: &Line<'a>) -> Result<(Matrix4<f32>, &'a str), ParseError> {
+    line.expect_tokens(15)?;
+    let t = Matrix4::new(
+        line.float(5)?, //a
+        line.float(8)?, //d
+        line.float(11)?, //g
+        0.0,
+        line.float(6)?, //b
+        line.float(9)?, //e
+        line.float(12)?, //h
+        0.0,
+        line.float(7)?, //c
+        line.float(10)?, //f
+        line.float(13)?, //i
+        0.0,
+        line.float(2)?, //x
+        line.float(3)?, //y
+        line.float(4)?, //z
+        1.0,
+    );
+    Ok((t, line.rest(14)))
+}
+
+fn parse_edge(line: &Line) -> Result<[Point3<f32>; 2], ParseError> {
+    line.expect_tokens(8)?;
+    Ok([line.point(2)?, line.point(5)?])
+}
+
+fn parse_conditional_edge(line: &Line) -> Result<[Point3<f32>; 4], ParseError> {
+    line.expect_tokens(14)?;
+    Ok([line.point(2)?, line.point(5)?, line.point(8)?, line.point(11)?])
+}
+
+fn parse_triangle(line: &Line) -> Result<[Point3<f32>; 3], ParseError> {
+    line.expect_tokens(11)?;
+    Ok([line.point(2)?, line.point(5)?, line.point(8)?])
+}
+
+fn parse_quad(line: &Line) -> Result<[Point3<f32>; 4], ParseError> {
+    line.expect_tokens(14)?;
+    Ok([line.point(2)?, line.point(5)?, line.point(8)?, line.point(11)?])
+}
+
+/// Quads bent by more than this many degrees across their diagonal are
+/// reported as not planar.
+const MAX_QUAD_WARP: f32 = 3.0;
+
+/// Splits a quad into two triangles with the same winding as it. Convex
+/// quads are split along the a–c diagonal, concave ones along the diagonal
+/// through the corner that turns the wrong way, and bow-ties are untangled
+/// first, keeping the winding of their first three points. Also returns
+/// what was wrong with the quad, if anything.
+fn triangulate_quad(quad: [Point3<f32>; 4]) -> ([[Point3<f32>; 3]; 2], Option<String>) {
+    let split = |[a, b, c, d]: [Point3<f32>; 4]| [[a, b, c], [c, d, a]];
+    let [a, b, c, d] = quad;
+    // Which way the outline turns at each corner.
+    let turns: Vec<Vector3<f32>> = (0..4)
+        .map(|i| (quad[i] - quad[(i + 3) % 4]).cross(quad[(i + 1) % 4] - quad[i]))
+        .collect();
+    let reference = turns.iter().fold(Vector3::zero(), |a, &t| if t.magnitude2() > a.magnitude2() { t } else { a });
+    let backwards: Vec<usize> = (0..4).filter(|&i| turns[i].dot(reference) < 0.0).collect();
+    // Opposite corners' turns add up to the same thing, twice the quad's
+    // vector area, so no more than two corners can turn against the largest
+    // turn, and a concave quad's reflex corner is never the largest.
+    let (triangles, problem) = match backwards[..] {
+        [] => (split(quad), None),
+        [reflex] => {
+            let triangles = if reflex % 2 == 0 { split(quad) } else { split([b, c, d, a]) };
+            (triangles, Some("concave quad"))
+        }
+        _ => {
+            let untangled = if (b - a).cross(d - b).dot(turns[1]) > 0.0 { [a, b, d, c] } else { [a, d, b, c] };
+            (split(untangled), Some("bow-tie quad, drawn with its points reordered"))
+        }
+    };
+    if problem.is_some() {
+        return (triangles, problem.map(String::from));
+    }
+    let normals = triangles.map(|[a, b, c]| (b - a).cross(c - b));
+    let warped = normals[0].magnitude2() > 0.0
+        && normals[1].magnitude2() > 0.0
+        && normals[0].angle(normals[1]) > cgmath::Deg(MAX_QUAD_WARP).into();
+    (triangles, if warped { Some("quad isn't planar".into()) } else { None })
+}
+
+/// The meta commands at the top of a file that describe it.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FileHeader {
+    /// The text of the first line, e.g. "Brick  1 x  2".
+    pub title: String,
+    pub name: Option<String>,
+    pub author: Option<String>,
+    /// The full `!LDRAW_ORG` text, e.g. "Part UPDATE 2004-03".
+    pub ldraw_org: Option<String>,
+    pub license: Option<String>,
+    pub category: Option<String>,
+    pub keywords: Vec<String>,
+    pub history: Vec<String>,
+    pub help: Vec<String>,
+}
+
+impl FileHeader {
+    /// The header of a whole file's text, with any line endings.
+    pub fn parse(text: &str) -> Self {
+        parse_header(Path::new(""), 0, &document::normalize(text))
+    }
+
+    /// The file type from `!LDRAW_ORG`, e.g. "Part", "Unofficial_Primitive"
+    /// or "Model".
+    pub fn file_type(&self) -> Option<&str> {
+        self.ldraw_org.as_deref().and_then(|org| org.split_whitespace().next())
+    }
+
+    /// Whether the header marks the file as standing in for another one.
+    pub fn redirect_kind(&self) -> Option<RedirectKind> {
+        if self.title.starts_with("~Moved to") {
+            Some(RedirectKind::Moved)
+        } else if self.ldraw_org.as_deref().and_then(|org| org.split_whitespace().nth(1)) == Some("Alias") {
+            Some(RedirectKind::Alias)
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RedirectKind {
+    /// A part that has been renumbered, left behind as a "~Moved to" stub.
+    Moved,
+    /// Another number for the same part, marked `Part Alias`.
+    Alias,
+}
+
+/// A file that does nothing but reference another one: a part that has
+/// moved, or an alias.
+#[derive(Clone, Debug)]
+pub struct Redirect {
+    pub kind: RedirectKind,
+    /// The name the file was referenced by, lowercased.
+    pub from: String,
+    /// The name of the file it references, as written in it.
+    pub to: String,
+    /// Where `to` is placed relative to `from`, which is usually the
+    /// identity.
+    pub transform: Matrix4<f32>,
+}
+
+/// Reads the header of `text`, which ends at the first drawing command.
+fn parse_header(path: &Path, line_offset: usize, text: &str) -> FileHeader {
+    let mut header = FileHeader::default();
+    let mut first = true;
+    for (index, text_line) in text.lines().enumerate() {
+        let line = Line::new(path, line_offset + index + 1, text_line);
+        match line.tokens.first() {
+            None => continue,
+            Some(&"0") => {}
+            Some(_) => break,
+        }
+        let rest = line.rest(2);
+        match line.tokens.get(1) {
+            Some(&"Name:") => header.name = Some(rest.into()),
+            Some(&"Author:") => header.author = Some(rest.into()),
+            Some(&"!LDRAW_ORG") => header.ldraw_org = Some(rest.into()),
+            Some(&"!LICENSE") => header.license = Some(rest.into()),
+            Some(&"!CATEGORY") => header.category = Some(rest.into()),
+            Some(&"!KEYWORDS") => header.keywords.extend(
+                rest.split(',')
+                    .map(|k| k.trim())
+                    .filter(|k| !k.is_empty())
+                    .map(|k| k.to_string()),
+            ),
+            Some(&"!HISTORY") => header.history.push(rest.into()),
+            Some(&"!HELP") => header.help.push(rest.into()),
+            _ if first => header.title = line.rest(1).into(),
+            _ => {}
+        }
+        first = false;
+    }
+    header
+}
+
+/// A file embedded in a multi-part document (MPD) with `0 FILE`.
+#[derive(Clone, Debug)]
+pub struct Submodel {
+    /// The name from the `0 FILE` line, as written.
+    pub name: String,
+    /// The MPD the submodel is embedded in.
+    pub path: PathBuf,
+    /// Line number of the `0 FILE` line.
+    pub line: usize,
+    text: String,
+}
+
+/// The submodel `filename` refers to when it's referenced from the file at
+/// `referenced_from`, which must be the MPD the submodel is embedded in.
+/// Files that are loaded directly can be submodels too.
+fn find_submodel<'a>(
+    submodels: &'a [Submodel],
+    submodel_index: &HashMap<String, usize>,
+    filename: &str,
+    referenced_from: Option<&Path>,
+) -> Option<&'a Submodel> {
+    let submodel = &submodels[*submodel_index.get(filename)?];
+    match referenced_from {
+        Some(path) if path != submodel.path => None,
+        _ => Some(submodel),
+    }
+}
+
+/// Splits an MPD into its embedded files. Returns `None` if the text isn't
+/// an MPD, i.e. it doesn't start with a `0 FILE` line.
+fn split_mpd(path: &Path, text: &str) -> Option<Vec<Submodel>> {
+    let mut submodels: Vec<Submodel> = Vec::new();
+    let mut in_file = false;
+    for (index, text_line) in text.lines().enumerate() {
+        let line = Line::new(path, index + 1, text_line);
+        match (line.tokens.first(), line.tokens.get(1)) {
+            (Some(&"0"), Some(&"FILE")) => {
+                submodels.push(Submodel {
+                    name: line.rest(2).into(),
+                    path: path.into(),
+                    line: index + 1,
+                    text: String::new(),
+                });
+                in_file = true;
+            }
+            (Some(&"0"), Some(&"NOFILE")) => in_file = false,
+            (None, _) if submodels.is_empty() => {}
+            _ if submodels.is_empty() => return None,
+            _ => {
+                if in_file {
+                    let submodel = submodels.last_mut().unwrap();
+                    submodel.text.push_str(text_line);
+                    submodel.text.push('\n');
+                }
+            }
+        }
+    }
+    if submodels.is_empty() {
+        None
+    } else {
+        Some(submodels)
+    }
+}
+
+/// The contents of a file to parse, and where they came from.
+struct Source {
+    path: PathBuf,
+    /// The resolved name the file is cached under: its path, qualified with
+    /// the section name for MPD sections.
+    key: String,
+    /// Number of lines in `path` that come before `text`.
+    line_offset: usize,
+    text: String,
+}
+
+/// A file's own lines, and everything else that can be worked out from the
+/// file on its own, which is what gets done in parallel.
+struct ParsedSource {
+    mesh: Geometry,
+    header: FileHeader,
+    diagnostics: Vec<ParseError>,
+    /// Where each subfile in `mesh` is referenced, in the same order, for
+    /// reporting the ones that can't be read.
+    references: Vec<ParseError>,
+}
+
+/// What geometry in the disk cache depends on besides the files it was
+/// built from: the colors that codes resolve to, and where subfiles were
+/// looked for.
+fn cache_context(library: &LibraryConfig, colors: &ColorTable) -> u64 {
+    let fingerprints = [library.fingerprint(), colors.fingerprint()];
+    fnv1a(fingerprints.iter().flat_map(|fingerprint| fingerprint.to_le_bytes().to_vec()))
+}
+
+/// Reads a file from the library without decoding it.
+fn read_library_data(library: &LibraryConfig, filename: &str, referenced_from: Option<&Path>) -> Result<(PathBuf, Vec<u8>), ParseError> {
+    match library.open(filename, referenced_from) {
+        Ok(file) => Ok(file),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Err(ParseError::new(Path::new(filename), ParseErrorKind::FileNotFound)),
+        Err(e) => Err(ParseError::new(Path::new(filename), ParseErrorKind::Io(e.to_string()))),
+    }
+}
+
+/// Reads a file from the library as text with a byte order mark removed and
+/// `\n` line endings. Files that aren't valid UTF-8 are still read, along
+/// with a diagnostic pointing at the first invalid byte.
+fn read_library_file(
+    library: &LibraryConfig,
+    filename: &str,
+    referenced_from: Option<&Path>,
+) -> Result<(PathBuf, String, Option<ParseError>), ParseError> {
+    let (path, data) = read_library_data(library, filename, referenced_from)?;
+    let (text, invalid) = document::decode_normalized(&data);
+    let warning = invalid.map(|offset| invalid_utf8(&path, &data, offset));
+    Ok((path, text, warning))
+}
+
+/// A diagnostic for the first byte of a file that isn't valid UTF-8, at
+/// `offset` in `data`. The line and column are in the text as it's parsed,
+/// i.e. after `document::normalize`.
+fn invalid_utf8(path: &Path, data: &[u8], offset: usize) -> ParseError {
+    let before = String::from_utf8_lossy(&data[..offset]);
+    let before = document::normalize(&before);
+    let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+    ParseError {
+        line: before.matches('\n').count() + 1,
+        column: before.len() - line_start + 1,
+        ..ParseError::new(path, ParseErrorKind::InvalidUtf8)
+    }
+}
+
+/// What reading a referenced file gave.
+enum Read {
+    /// A section of the current MPD, which is already in memory.
+    Submodel,
+    /// A file that has already been read under another lookup name, by its
+    /// resolved name.
+    Known(String),
+    File(Result<(PathBuf, String, Option<ParseError>), ParseError>),
+    /// Resolved geometry from the disk cache under the file's resolved name,
+    /// by whether it's inverted.
+    Cached(String, Vec<(bool, Geometry, FileHeader)>),
+}
+
+/// Maps `f` over `items`, on the thread pool if `parallel` is set. The
+/// results are in the same order either way.
+fn map<T: Sync, U: Send, F: Fn(&T) -> U + Sync + Send>(parallel: bool, items: &[T], f: F) -> Vec<U> {
+    if parallel {
+        items.par_iter().map(f).collect()
+    } else {
+        items.iter().map(f).collect()
+    }
+}
+
+/// How long loading has taken, to see what parallel loading gains.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct LoadStats {
+    /// The number of files read.
+    pub files: usize,
+    /// The number of files loaded from the disk cache instead.
+    pub cached: usize,
+    /// Time spent reading and parsing files, added up over all threads.
+    pub work: Duration,
+    /// Time that reading and parsing actually took.
+    pub elapsed: Duration,
+}
+
+impl LoadStats {
+    /// How many times faster loading was than doing the same work on one
+    /// thread.
+    pub fn speedup(&self) -> f32 {
+        if self.elapsed.as_secs_f32() > 0.0 {
+            self.work.as_secs_f32() / self.elapsed.as_secs_f32()
+        } else {
+            1.0
+        }
+    }
+}
+
+/// Deeper than any official part or sensibly built model goes.
+const DEFAULT_MAX_DEPTH: usize = 64;
+
+pub struct Parser {
+    /// Flattened geometry by resolved name, shared with every caller.
+    cache: HashMap<CacheKey, Arc<Geometry>>,
+    /// The own lines of every file read so far, by resolved name.
+    meshes: HashMap<String, Arc<Geometry>>,
+    /// The resolved name of every lookup name that has been looked up, so
+    /// that files are only searched for once.
+    resolved: HashMap<String, String>,
+    /// Why each lookup name that couldn't be read failed.
+    failed: HashMap<String, ParseError>,
+    /// The path each resolved name was read from.
+    paths: HashMap<String, PathBuf>,
+    /// The resolved name of every file read from the library that isn't an
+    /// MPD, by path, so that a file found under another lookup name isn't
+    /// read again.
+    files: HashMap<PathBuf, String>,
+    disk_cache: Option<DiskCache>,
+    /// What disk cache entries depend on besides their files: the colors and
+    /// the library sources.
+    cache_context: u64,
+    /// The resolved name of every lookup name that has been looked for in
+    /// the disk cache, which can be found without reading the file.
+    located: HashMap<String, String>,
+    library: LibraryConfig,
+    diagnostics: Diagnostics,
+    submodels: Vec<Submodel>,
+    submodel_index: HashMap<String, usize>,
+    /// The submodels of every MPD read so far, by file name, so that they
+    /// can be indexed again when the MPD itself comes from the cache.
+    documents: HashMap<String, Vec<Submodel>>,
+    headers: HashMap<String, FileHeader>,
+    colors: ColorTable,
+    parallel: bool,
+    stats: LoadStats,
+    /// The resolved names of the files being flattened or instanced, from
+    /// the outermost one in, along with the names they were referenced by.
+    stack: Vec<(String, String)>,
+    max_depth: usize,
+    /// How many references have been left out to break cycles or to keep
+    /// within `max_depth`.
+    truncated: usize,
+    redirects: Vec<Redirect>,
+    /// The reference in each moved or alias file, by resolved name.
+    redirected: HashMap<String, PartReference>,
+}
+
+impl Parser {
+    /// A parser for the library at `ldraw_directory` and its `Unofficial`
+    /// folder, or in the archive at `ldraw_directory`.
+    pub fn new(ldraw_directory: &str) -> io::Result<Self> {
+        Ok(Self::with_library(LibraryConfig::new(ldraw_directory)?))
+    }
+
+    pub fn with_library(library: LibraryConfig) -> Self {
+        let mut diagnostics = Diagnostics::default();
+        let colors = match library.open_ldconfig() {
+            Ok((path, data)) => {
+                let (text, invalid) = document::decode_normalized(&data);
+                if let Some(offset) = invalid {
+                    diagnostics.report(invalid_utf8(&path, &data, offset));
+                }
+                ColorTable::parse(&text)
+            }
+            Err(e) => {
+                if e.kind() != io::ErrorKind::NotFound {
+                    diagnostics.report(ParseError::new(Path::new("LDConfig.ldr"), ParseErrorKind::Io(e.to_string())));
+                }
+                ColorTable::builtin()
+            }
+        };
+        Self {
+            cache: HashMap::new(),
+            meshes: HashMap::new(),
+            resolved: HashMap::new(),
+            failed: HashMap::new(),
+            paths: HashMap::new(),
+            files: HashMap::new(),
+            disk_cache: None,
+            cache_context: cache_context(&library, &colors),
+            located: HashMap::new(),
+            library,
+            diagnostics,
+            submodels: Vec::new(),
+            submodel_index: HashMap::new(),
+            documents: HashMap::new(),
+            headers: HashMap::new(),
+            colors,
+            parallel: true,
+            stats: LoadStats::default(),
+            stack: Vec::new(),
+            max_depth: DEFAULT_MAX_DEPTH,
+            truncated: 0,
+            redirects: Vec::new(),
+            redirected: HashMap::new(),
+        }
+    }
+
+    pub fn library(&self) -> &LibraryConfig {
+        &self.library
+    }
+
+    /// The colors from the library's LDConfig.ldr, or the built-in ones if
+    /// there isn't one.
+    pub fn colors(&self) -> &ColorTable {
+        &self.colors
+    }
+
+    /// Replaces the color table. Anything already loaded keeps its colors.
+    pub fn set_colors(&mut self, colors: ColorTable) {
+        self.cache_context = cache_context(&self.library, &colors);
+        self.colors = colors;
+    }
+
+    /// Whether files are read and parsed on a thread pool. The results are
+    /// the same either way.
+    pub fn set_parallel(&mut self, parallel: bool) {
+        self.parallel = parallel;
+    }
+
+    /// Where resolved part geometry is saved between runs, if anywhere. Parts
+    /// that come from the cache aren't parsed, so problems in them are only
+    /// reported the first time.
+    pub fn set_disk_cache(&mut self, disk_cache: Option<DiskCache>) {
+        self.disk_cache = disk_cache;
+    }
+
+    /// How deeply subfiles can be nested. References below that are left
+    /// out and reported.
+    pub fn set_max_depth(&mut self, max_depth: usize) {
+        self.max_depth = max_depth;
+    }
+
+    /// Totals for every file read so far.
+    pub fn stats(&self) -> LoadStats {
+        self.stats
+    }
+
+    /// Non-fatal problems (skipped lines, missing subparts) found so far.
+    /// Nothing is printed, so callers should show these themselves.
+    pub fn diagnostics(&self) -> &[ParseError] {
+        &self.diagnostics.entries
+    }
+
+    pub fn take_diagnostics(&mut self) -> Vec<ParseError> {
+        std::mem::take(&mut self.diagnostics.entries)
+    }
+
+    /// The moved and alias parts found so far, in the order they were
+    /// found, e.g. for updating models to current part numbers. They're
+    /// followed when loading, so their targets are what gets drawn.
+    pub fn redirects(&self) -> &[Redirect] {
+        &self.redirects
+    }
+
+    /// The files embedded in the most recently loaded MPD, in file order.
+    /// The first one is the main model.
+    pub fn submodels(&self) -> &[Submodel] {
+        &self.submodels
+    }
+
+    /// Loads a file and all of its subfiles. Only problems with the file
+    /// itself are returned as errors; problems in its contents or subfiles
+    /// are reported as diagnostics and skipped.
+    ///
+    /// The geometry is shared with the parser's cache, so loading the same
+    /// file again is cheap.
+    pub fn load(&mut self, filename: &str) -> Result<Arc<Geometry>, ParseError> {
+        self.index_document(filename);
+        self.read_file(filename, false, None)
+    }
+
+    /// Loads a file as a tree of the files it references, without
+    /// flattening it. Problems are handled as for `load`, and subfiles that
+    /// can't be loaded are left out of the tree.
+    pub fn load_scene(&mut self, filename: &str) -> Result<Scene, ParseError> {
+        self.index_document(filename);
+        let mut meshes = HashMap::new();
+        let root = PartReference {
+            name: filename.into(),
+            color: LdrawColor::Main,
+            edge_color: LdrawColor::Complement,
+            transform: Matrix4::identity(),
+            inverted: false,
+            clip: true,
+        };
+        let root = self.instance(&root, None, &mut meshes)?;
+        Ok(Scene { root, meshes })
+    }
+
+    /// Loads one of the submodels of the most recently loaded MPD, e.g. to
+    /// step through it on its own.
+    pub fn load_submodel(&mut self, name: &str) -> Result<Arc<Geometry>, ParseError> {
+        self.read_file(name, false, None)
+    }
+
+    /// The header of a file that has been loaded, either directly or as a
+    /// subfile of something else.
+    pub fn header(&self, filename: &str) -> Option<&FileHeader> {
+        let filename = filename.to_lowercase().replace('\\', "/");
+        let name = self.lookup_name(&filename, None);
+        match self.resolved.get(&name).or_else(|| self.located.get(&name)) {
+            Some(key) => self.headers.get(key),
+            // Subfiles are looked up from the file that references them.
+            None => self.headers.get(&self.library.find(&filename, None)?.display().to_string()),
+        }
+    }
+
+    /// Reads just the header of a file, without loading its geometry or any
+    /// of its subfiles.
+    pub fn read_header(&mut self, filename: &str) -> Result<FileHeader, ParseError> {
+        if let Some(header) = self.header(filename) {
+            return Ok(header.clone());
+        }
+        let filename = filename.to_lowercase().replace('\\', "/");
+        let source = self.find_file(&filename, None)?;
+        Ok(parse_header(&source.path, source.line_offset, &source.text))
+    }
+
+    /// Makes the submodels of `filename` the current ones, if it's an MPD
+    /// that has been read before.
+    fn index_document(&mut self, filename: &str) {
+        self.submodels.clear();
+        self.submodel_index.clear();
+        let name = filename.to_lowercase().replace('\\', "/");
+        if let Some(submodels) = self.documents.get(&name).cloned() {
+            self.add_submodels(submodels);
+        }
+    }
+
+    /// Reads a file from the library as a document that can be edited and
+    /// written back, without loading any of its subfiles. MPDs are read
+    /// whole, and the file is decoded as `LdrawDocument::load` does.
+    pub fn read_document(&self, filename: &str) -> Result<LdrawDocument, ParseError> {
+        let filename = filename.to_lowercase().replace('\\', "/");
+        let (_, data) = read_library_data(&self.library, &filename, None)?;
+        Ok(LdrawDocument::from_bytes(&data))
+    }
+
+    fn add_submodels(&mut self, submodels: Vec<Submodel>) {
+        for submodel in submodels {
+            let name = submodel.name.to_lowercase().replace('\\', "/");
+            if !self.submodel_index.contains_key(&name) {
+                self.submodel_index.insert(name, self.submodels.len());
+                self.submodels.push(submodel);
+            }
+        }
+    }
+
+    /// The submodel of the current MPD a reference is to, if any. Only the
+    /// MPD's own sections can refer to its submodels, so that library files
+    /// it uses still get the library files of the same name.
+    fn submodel(&self, filename: &str, referenced_from: Option<&Path>) -> Option<&Submodel> {
+        find_submodel(&self.submodels, &self.submodel_index, filename, referenced_from)
+    }
+
+    /// The name a reference is looked up under. Submodels are qualified with
+    /// the MPD they came from so that they don't clash with submodels of the
+    /// same name in other documents. Files that aren't in the library are
+    /// found next to the file that references them, so the same name can
+    /// mean different files from different directories.
+    fn lookup_name(&self, filename: &str, referenced_from: Option<&Path>) -> String {
+        if let Some(submodel) = self.submodel(filename, referenced_from) {
+            return format!("{}:{}", submodel.path.display(), filename);
+        }
+        match referenced_from.and_then(Path::parent).filter(|_| self.library.search_relative) {
+            Some(directory) => format!("{}:{}", directory.display(), filename),
+            None => filename.into(),
+        }
+    }
+
+    fn submodel_source(&self, filename: &str, referenced_from: Option<&Path>) -> Option<Source> {
+        let submodel = self.submodel(filename, referenced_from)?;
+        Some(Source {
+            path: submodel.path.clone(),
+            key: format!("{}:{}", submodel.path.display(), filename),
+            line_offset: submodel.line,
+            text: submodel.text.clone(),
+        })
+    }
+
+    /// Finds a file among the submodels of the current MPD or in the
+    /// library. `referenced_from` is the file that refers to it, if any.
+    fn find_file(&mut self, filename: &str, referenced_from: Option<&Path>) -> Result<Source, ParseError> {
+        if let Some(source) = self.submodel_source(filename, referenced_from) {
+            return Ok(source);
+        }
+
+        let (path, text, warning) = read_library_file(&self.library, filename, referenced_from)?;
+        if let Some(warning) = warning {
+            self.diagnostics.report(warning);
+        }
+        Ok(self.file_source(filename, path, text))
+    }
+
+    /// The source for a file read from the library. For an MPD this is its
+    /// main model, and its sections become the current submodels.
+    fn file_source(&mut self, filename: &str, path: PathBuf, text: String) -> Source {
+        match split_mpd(&path, &text) {
+            Some(submodels) => {
+                let main = submodels[0].clone();
+                self.documents.insert(filename.into(), submodels.clone());
+                self.add_submodels(submodels);
+                Source {
+                    key: format!("{}:{}", path.display(), main.name.to_lowercase().replace('\\', "/")),
+                    path,
+                    line_offset: main.line,
+                    text: main.text,
+                }
+            }
+            None => {
+                self.files.insert(path.clone(), path.display().to_string());
+                Source {
+                    key: path.display().to_string(),
+                    path,
+                    line_offset: 0,
+                    text,
+                }
+            }
+        }
+    }
+
+    /// The color and edge color for the color code of a type 1-5 line.
+    fn color(colors: &ColorTable, line: &Line, diagnostics: &mut Vec<ParseError>) -> (LdrawColor, LdrawColor) {
+        match colors.lookup(line.tokens[1]) {
+            Some(colors) => colors,
+            None => {
+                diagnostics.push(line.error(1, ParseErrorKind::UnknownColor));
+                (LdrawColor::Main, LdrawColor::Complement)
+            }
+        }
+    }
+
+    /// The file's own lines, parsed once and shared by every reference to
+    /// it, along with the resolved name they're cached under.
+    fn read_mesh(&mut self, filename: &str, referenced_from: Option<&Path>) -> Result<(String, Arc<Geometry>), ParseError> {
+        let filename = filename.to_lowercase().replace('\\', "/");
+        let name = self.lookup_name(&filename, referenced_from);
+        if let Some(e) = self.failed.get(&name) {
+            return Err(e.clone());
+        }
+        if !self.resolved.contains_key(&name) {
+            let start = Instant::now();
+            let result = self.read_meshes(&filename, referenced_from);
+            self.stats.elapsed += start.elapsed();
+            result?;
+        }
+        let key = &self.resolved[&self.lookup_name(&filename, referenced_from)];
+        Ok((key.clone(), self.meshes[key].clone()))
+    }
+
+    /// Reads a file and every file under it that hasn't been read yet. Each
+    /// round reads and parses the distinct files referenced by the previous
+    /// one in parallel, and then adds them in the order they're referenced
+    /// so that the results don't depend on timing. Only problems with
+    /// `filename` itself are returned, and problems with the files under it
+    /// are reported against the lines that reference them.
+    fn read_meshes(&mut self, filename: &str, referenced_from: Option<&Path>) -> Result<(), ParseError> {
+        // Each reference is a name, the file it's referenced from and where.
+        let mut round = vec![(filename.to_string(), referenced_from.map(PathBuf::from), None::<ParseError>)];
+        let mut first_round = true;
+        while !round.is_empty() {
+            let mut names: Vec<(String, String, Option<PathBuf>)> = Vec::new();
+            let mut locations: HashMap<String, Vec<ParseError>> = HashMap::new();
+            for (name, referenced_from, location) in round {
+                let name: String = name.to_lowercase().replace('\\', "/");
+                let lookup_name = self.lookup_name(&name, referenced_from.as_deref());
+                if self.resolved.contains_key(&lookup_name) {
+                    continue;
+                }
+                // Files that came from the disk cache are only parsed when
+                // they're loaded themselves.
+                if location.is_some() && self.located.contains_key(&lookup_name) {
+                    continue;
+                }
+                if let (Some(e), Some(location)) = (self.failed.get(&lookup_name), &location) {
+                    let kind = e.kind.clone();
+                    self.diagnostics.report(ParseError { kind, ..location.clone() });
+                    continue;
+                }
+                locations.entry(lookup_name.clone()).or_default().extend(location);
+                if !names.iter().any(|(_, n, _)| *n == lookup_name) {
+                    names.push((name, lookup_name, referenced_from));
+                }
+            }
+
+            let (library, files) = (&self.library, &self.files);
+            let (submodels, submodel_index) = (&self.submodels, &self.submodel_index);
+            // The file being loaded is always parsed, but the files under it
+            // can come from the disk cache.
+            let disk_cache = if first_round { None } else { self.disk_cache.as_ref() };
+            let context = self.cache_context;
+            let reads = map(self.parallel, &names, |(name, _, referenced_from)| {
+                let start = Instant::now();
+                let read = if find_submodel(submodels, submodel_index, name, referenced_from.as_deref()).is_some() {
+                    Read::Submodel
+                } else {
+                    let path = library.find(name, referenced_from.as_deref());
+                    let known = path.as_ref().and_then(|path| files.get(path)).map(|key| Read::Known(key.clone()));
+                    let cached = || disk_cache.and_then(|disk_cache| {
+                        let key = path.as_ref()?.display().to_string();
+                        let entries: Vec<_> = [false, true]
+                            .iter()
+                            .filter_map(|&inverted| disk_cache.get(&key, inverted, context, library).map(|(g, h)| (inverted, g, h)))
+                            .collect();
+                        if entries.is_empty() {
+                            None
+                        } else {
+                            Some(Read::Cached(key, entries))
+                        }
+                    });
+                    known
+                        .or_else(cached)
+                        .unwrap_or_else(|| Read::File(read_library_file(library, name, referenced_from.as_deref())))
+                };
+                (read, start.elapsed())
+            });
+            first_round = false;
+
+            let mut sources = Vec::new();
+            let mut source_names = Vec::new();
+            for ((name, lookup_name, referenced_from), (read, time)) in names.iter().zip(reads) {
+                self.stats.work += time;
+                let lookup_name = lookup_name.clone();
+                let source = match read {
+                    Read::Submodel => self.submodel_source(name, referenced_from.as_deref()).unwrap(),
+                    Read::Known(key) => {
+                        self.resolved.insert(lookup_name, key);
+                        continue;
+                    }
+                    Read::File(Ok((path, text, warning))) => {
+                        if let Some(warning) = warning {
+                            self.diagnostics.report(warning);
+                        }
+                        self.file_source(name, path, text)
+                    }
+                    Read::Cached(key, entries) => {
+                        for (inverted, geometry, header) in entries {
+                            self.stats.cached += 1;
+                            self.headers.insert(key.clone(), header);
+                            self.cache.insert(CacheKey { name: key.clone(), inverted }, Arc::new(geometry));
+                        }
+                        self.located.insert(lookup_name, key);
+                        continue;
+                    }
+                    Read::File(Err(e)) => {
+                        self.failed.insert(lookup_name.clone(), e.clone());
+                        let locations = locations.remove(&lookup_name).unwrap_or_default();
+                        if locations.is_empty() {
+                            return Err(e);
+                        }
+                        for location in locations {
+                            self.diagnostics.report(ParseError { kind: e.kind.clone(), ..location });
+                        }
+                        continue;
+                    }
+                };
+                // Reading an MPD indexes its sections, which can change its
+                // name.
+                self.resolved.insert(lookup_name, source.key.clone());
+                self.resolved.insert(self.lookup_name(name, referenced_from.as_deref()), source.key.clone());
+                // Skip the same file under another name.
+                if !self.meshes.contains_key(&source.key) && !sources.iter().any(|s: &Source| s.key == source.key) {
+                    sources.push(source);
+                    source_names.push(name.clone());
+                }
+            }
+
+            let colors = &self.colors;
+            let parsed = map(self.parallel, &sources, |source| {
+                let start = Instant::now();
+                (Self::parse_source(source, colors), start.elapsed())
+            });
+
+            round = Vec::new();
+            for ((source, name), (parsed, time)) in sources.into_iter().zip(source_names).zip(parsed) {
+                self.stats.work += time;
+                self.stats.files += 1;
+                for diagnostic in parsed.diagnostics {
+                    self.diagnostics.report(diagnostic);
+                }
+                if let Some((kind, reference)) = Self::redirect(&parsed.header, &parsed.mesh) {
+                    self.redirects.push(Redirect {
+                        kind,
+                        from: name,
+                        to: reference.name.clone(),
+                        transform: reference.transform,
+                    });
+                    self.redirected.insert(source.key.clone(), reference.clone());
+                }
+                let parts = parsed.mesh.steps.iter().flat_map(|step| &step.parts);
+                for (part, location) in parts.zip(parsed.references) {
+                    round.push((part.name.clone(), Some(source.path.clone()), Some(location)));
+                }
+                self.headers.insert(source.key.clone(), parsed.header);
+                self.paths.insert(source.key.clone(), source.path);
+                self.meshes.insert(source.key, Arc::new(parsed.mesh));
+            }
+        }
+        Ok(())
+    }
+
+    /// The kind of a moved or alias file and the file it references. Files
+    /// marked as one that draw anything else aren't followed.
+    fn redirect<'a>(header: &FileHeader, mesh: &'a Geometry) -> Option<(RedirectKind, &'a PartReference)> {
+        let kind = header.redirect_kind()?;
+        let mut parts = mesh.steps.iter().flat_map(|step| &step.parts);
+        match (parts.next(), parts.next()) {
+            (Some(reference), None) if mesh.polygons.is_empty() && mesh.edges.is_empty() && mesh.conditional_edges.is_empty() => {
+                Some((kind, reference))
+            }
+            _ => None,
+        }
+    }
+
+    /// Everything a file draws, with its subfiles resolved.
+    fn read_file(&mut self, filename: &str, inverted: bool, referenced_from: Option<&Path>) -> Result<Arc<Geometry>, ParseError> {
+        if let Some(geometry) = self.read_disk_cache(filename, inverted, referenced_from) {
+            return Ok(geometry);
+        }
+        let (name, mesh) = self.read_mesh(filename, referenced_from)?;
+        let key = CacheKey { name, inverted };
+        if let Some(geometry) = self.cache.get(&key) {
+            return Ok(geometry.clone());
+        }
+        self.enter(&key.name, filename)?;
+        let truncated = self.truncated;
+        let path = self.paths[&key.name].clone();
+        let geometry = Arc::new(self.flatten(&mesh, inverted, &path));
+        self.stack.pop();
+        // Which references break a cycle or go too deep depends on where
+        // loading started, so geometry missing any isn't kept.
+        if self.truncated == truncated {
+            self.write_disk_cache(&key, &geometry, filename, referenced_from);
+            self.cache.insert(key, geometry.clone());
+        }
+        Ok(geometry)
+    }
+
+    /// Pushes a file, referenced as `filename`, onto the stack of files being
+    /// resolved, failing if it's already on it or the stack is full. Cycles
+    /// are reported with the names the files were referenced by.
+    fn enter(&mut self, name: &str, filename: &str) -> Result<(), ParseError> {
+        let kind = if let Some(start) = self.stack.iter().position(|(n, _)| n == name) {
+            let mut chain: Vec<String> = self.stack[start..].iter().map(|(_, filename)| filename.clone()).collect();
+            chain.push(filename.into());
+            ParseErrorKind::Cycle(chain)
+        } else if self.stack.len() >= self.max_depth {
+            ParseErrorKind::TooDeep(self.max_depth)
+        } else {
+            self.stack.push((name.into(), filename.into()));
+            return Ok(());
+        };
+        let path = self.stack.last().and_then(|(parent, _)| self.paths.get(parent));
+        Err(ParseError {
+            token: Some(filename.into()),
+            ..ParseError::new(path.map_or(Path::new(filename), PathBuf::as_path), kind)
+        })
+    }
+
+    /// Reports a subfile that was left out while resolving a file. Other
+    /// problems with subfiles were reported when the file was parsed.
+    fn skip_reference(&mut self, error: ParseError) {
+        if let ParseErrorKind::Cycle(_) | ParseErrorKind::TooDeep(_) = error.kind {
+            self.truncated += 1;
+            self.diagnostics.report(error);
+        }
+    }
+
+    /// A library file's geometry from the disk cache, without parsing it or
+    /// any of its subfiles. Files that have already been parsed come from the
+    /// memory cache instead.
+    fn read_disk_cache(&mut self, filename: &str, inverted: bool, referenced_from: Option<&Path>) -> Option<Arc<Geometry>> {
+        let disk_cache = self.disk_cache.as_ref()?;
+        let filename = filename.to_lowercase().replace('\\', "/");
+        let name = self.lookup_name(&filename, referenced_from);
+        if self.submodel(&filename, referenced_from).is_some() || self.resolved.contains_key(&name) || self.failed.contains_key(&name) {
+            return None;
+        }
+        let key = match self.located.get(&name) {
+            Some(key) => key.clone(),
+            None => self.library.find(&filename, referenced_from)?.display().to_string(),
+        };
+        let key = CacheKey { name: key, inverted };
+        if let Some(geometry) = self.cache.get(&key) {
+            return Some(geometry.clone());
+        }
+        let (geometry, header) = disk_cache.get(&key.name, inverted, self.cache_context, &self.library)?;
+        let geometry = Arc::new(geometry);
+        self.stats.cached += 1;
+        self.located.insert(name, key.name.clone());
+        self.headers.insert(key.name.clone(), header);
+        self.cache.insert(key, geometry.clone());
+        Some(geometry)
+    }
+
+    /// Saves a part's resolved geometry to the disk cache, where `filename`
+    /// is the name it was referenced by. Other files, and parts with subfiles
+    /// that couldn't be read or aren't on disk, aren't saved.
+    fn write_disk_cache(&mut self, key: &CacheKey, geometry: &Geometry, filename: &str, referenced_from: Option<&Path>) {
+        let disk_cache = match &self.disk_cache {
+            Some(disk_cache) => disk_cache,
+            None => return,
+        };
+        // Moved and alias files are always read so that they're reported.
+        if self.redirected.contains_key(&key.name) {
+            return;
+        }
+        let header = match self.headers.get(&key.name) {
+            Some(header) if header.file_type().is_some_and(|t| t.ends_with("Part") || t.ends_with("Shortcut")) => header,
+            _ => return,
+        };
+        let dependencies = match self.dependencies(&key.name, filename, referenced_from) {
+            Some(dependencies) => dependencies,
+            None => return,
+        };
+        if let Err(e) = disk_cache.put(&key.name, key.inverted, self.cache_context, geometry, header, &dependencies) {
+            let path = disk_cache.directory().to_path_buf();
+            self.diagnostics.report(ParseError::new(&path, ParseErrorKind::Io(e.to_string())));
+        }
+    }
+
+    /// How a parsed file, referenced as `filename`, and every file under it
+    /// were found, or `None` if any of them couldn't be read or isn't on
+    /// disk.
+    fn dependencies(&self, key: &str, filename: &str, referenced_from: Option<&Path>) -> Option<Vec<Dependency>> {
+        let filename = filename.to_lowercase().replace('\\', "/");
+        let mut dependencies = vec![Dependency::find(&self.library, &filename, referenced_from)?];
+        let mut visited = vec![key.to_string()];
+        let mut pending = vec![key.to_string()];
+        while let Some(key) = pending.pop() {
+            let path = self.paths.get(&key)?;
+            for part in self.meshes.get(&key)?.steps.iter().flat_map(|step| &step.parts) {
+                let name = part.name.to_lowercase().replace('\\', "/");
+                let child = self.resolved.get(&self.lookup_name(&name, Some(path)))?;
+                if !dependencies.iter().any(|d| d.filename == name && d.referenced_from.as_deref() == Some(path)) {
+                    dependencies.push(Dependency::find(&self.library, &name, Some(path))?);
+                }
+                if !visited.contains(child) {
+                    visited.push(child.clone());
+                    pending.push(child.clone());
+                }
+            }
+        }
+        Some(dependencies)
+    }
+
+    /// Resolves the subfiles of a mesh read from `path`, keeping its steps.
+    fn flatten(&mut self, mesh: &Geometry, inverted: bool, path: &Path) -> Geometry {
+        let mut geometry = Geometry::default();
+        let mut start = Step::default();
+        for step in &mesh.steps {
+            for polygon in &mesh.polygons[start.polygons..step.polygons] {
+                let mut polygon = polygon.clone();
+                if inverted {
+                    polygon.points.reverse();
+                }
+                geometry.polygons.push(polygon);
+            }
+            geometry.edges.extend_from_slice(&mesh.edges[start.edges..step.edges]);
+            geometry
+                .conditional_edges
+                .extend_from_slice(&mesh.conditional_edges[start.conditional_edges..step.conditional_edges]);
+            for part in &step.parts {
+                let sub_geometry = match self.read_file(&part.name, inverted != part.inverted, Some(path)) {
+                    Ok(g) => g,
+                    Err(e) => {
+                        self.skip_reference(e);
+                        continue;
+                    }
+                };
+                let t = part.transform;
+                let (color, edge_color) = (&part.color, &part.edge_color);
+                for polygon in &sub_geometry.polygons {
+                    geometry.polygons.push(Polygon {
+                        points: polygon.points.iter().map(|p| t.transform_point(*p)).collect(),
+                        color: polygon.color.resolve(color, edge_color),
+                        // Subfiles of uncertified files, or ones referenced
+                        // with clipping off, can't be culled either.
+                        cull: polygon.cull && part.clip,
+                    });
+                }
+                for edge in &sub_geometry.edges {
+                    geometry.edges.push(Edge {
+                        points: [t.transform_point(edge.points[0]), t.transform_point(edge.points[1])],
+                        color: edge.color.resolve(color, edge_color),
+                    });
+                }
+                for edge in &sub_geometry.conditional_edges {
+                    geometry.conditional_edges.push(ConditionalEdge {
+                        points: [t.transform_point(edge.points[0]), t.transform_point(edge.points[1])],
+                        controls: [t.transform_point(edge.controls[0]), t.transform_point(edge.controls[1])],
+                        color: edge.color.resolve(color, edge_color),
+                    });
+                }
+            }
+            geometry.end_step(&mut step.parts.clone());
+            start = step.clone();
+        }
+        geometry
+    }
+
+    /// The instance tree for a reference from the file at `referenced_from`,
+    /// adding the meshes it uses to `meshes`.
+    fn instance(
+        &mut self,
+        reference: &PartReference,
+        referenced_from: Option<&Path>,
+        meshes: &mut HashMap<String, Arc<Geometry>>,
+    ) -> Result<PartInstance, ParseError> {
+        let (file, mesh) = self.read_mesh(&reference.name, referenced_from)?;
+        let path = self.paths[&file].clone();
+        self.enter(&file, &reference.name)?;
+        let child = |part: &PartReference| PartReference {
+            color: part.color.resolve(&reference.color, &reference.edge_color),
+            edge_color: part.edge_color.resolve(&reference.color, &reference.edge_color),
+            inverted: reference.inverted != part.inverted,
+            clip: reference.clip && part.clip,
+            ..part.clone()
+        };
+        // Moved and alias files are replaced by the file they reference.
+        if let Some(target) = self.redirected.get(&file) {
+            let target = PartReference {
+                transform: reference.transform * target.transform,
+                ..child(target)
+            };
+            let instance = self.instance(&target, Some(&path), meshes);
+            self.stack.pop();
+            return instance;
+        }
+        meshes.entry(file.clone()).or_insert_with(|| mesh.clone());
+        let mut children = Vec::new();
+        for part in mesh.steps.iter().flat_map(|step| &step.parts) {
+            let child = child(part);
+            match self.instance(&child, Some(&path), meshes) {
+                Ok(instance) => children.push(instance),
+                Err(e) => self.skip_reference(e),
+            }
+        }
+        self.stack.pop();
+        Ok(PartInstance {
+            file,
+            color: reference.color.clone(),
+            edge_color: reference.edge_color.clone(),
+            transform: reference.transform,
+            inverted: reference.inverted,
+            clip: reference.clip,
+            children,
+        })
+    }
+
+    /// Parses a file's own lines. Subfiles are only recorded in the steps.
+    fn parse_source(source: &Source, colors: &ColorTable) -> ParsedSource {
+        let mut geometry = Geometry::default();
+        let mut diagnostics = Vec::new();
+        let mut references = Vec::new();
+        let mut bfc = Bfc::new();
+        let mut step_parts = Vec::new();
+        for (index, text) in source.text.lines().enumerate() {
+            let line = Line::new(&source.path, source.line_offset + index + 1, text);
+            if line.tokens.len() < 2 {
+                continue;
+            }
+            let command_type = line.tokens[0];
+            let maybe_color = line.tokens[1];
+            if command_type != "0" {
+                bfc.draw();
+            }
+            match command_type {
+                "0" if maybe_color == "BFC" => {
+                    if let Err(e) = bfc.apply(&line) {
+                        diagnostics.push(e);
+                    }
+                }
+                "0" if maybe_color == "STEP" || maybe_color == "ROTSTEP" => {
+                    geometry.end_step(&mut step_parts);
+                }
+                "1" => {
+                    let (t, name) = match parse_subfile_ref(&line) {
+                        Ok(r) => r,
+                        Err(e) => {
+                            diagnostics.push(e);
+                            continue;
+                        }
+                    };
+                    let (color, edge_color) = Self::color(colors, &line, &mut diagnostics);
+                    step_parts.push(PartReference {
+                        name: name.into(),
+                        color,
+                        edge_color,
+                        transform: t,
+                        inverted: bfc.invert_next != (t.determinant() < 0.0),
+                        clip: bfc.cull(),
+                    });
+                    bfc.invert_next = false;
+                    references.push(ParseError {
+                        token: Some(name.into()),
+                        ..line.error(14, ParseErrorKind::FileNotFound)
+                    });
+                }
+                "2" => {
+                    let points = match parse_edge(&line) {
+                        Ok(points) => points,
+                        Err(e) => {
+                            diagnostics.push(e);
+                            continue;
+                        }
+                    };
+                    geometry.edges.push(Edge {
+                        points,
+                        color: Self::color(colors, &line, &mut diagnostics).0,
+                    });
+                }
+                "3" => {
+                    let [a, b, c] = match parse_triangle(&line) {
+                        Ok(points) => points,
+                        Err(e) => {
+                            diagnostics.push(e);
+                            continue;
+                        }
+                    };
+                    let points = if bfc.winding == Winding::CW {
+                        vec![c, b, a]
+                    } else {
+                        vec![a, b, c]
+                    };
+                    geometry.polygons.push(Polygon {
+                        points,
+                        color: Self::color(colors, &line, &mut diagnostics).0,
+                        cull: bfc.cull(),
+                    });
+                }
+                "4" => {
+                    let quad = match parse_quad(&line) {
+                        Ok(points) => points,
+                        Err(e) => {
+                            diagnostics.push(e);
+                            continue;
+                        }
+                    };
+                    let (triangles, problem) = triangulate_quad(quad);
+                    if let Some(reason) = problem {
+                        diagnostics.push(ParseError {
+                            token: None,
+                            ..line.error(2, ParseErrorKind::InvalidQuad(reason))
+                        });
+                    }
+                    let color = Self::color(colors, &line, &mut diagnostics).0;
+                    for mut points in triangles {
+                        if bfc.winding == Winding::CW {
+                            points.reverse();
+                        }
+                        geometry.polygons.push(Polygon {
+                            points: points.to_vec(),
+                            color: color.clone(),
+                            cull: bfc.cull(),
+                        });
+                    }
+                }
+                "5" => {
+                    let [a, b, c, d] = match parse_conditional_edge(&line) {
+                        Ok(points) => points,
+                        Err(e) => {
+                            diagnostics.push(e);
+                            continue;
+                        }
+                    };
+                    geometry.conditional_edges.push(ConditionalEdge {
+                        points: [a, b],
+                        controls: [c, d],
+                        color: Self::color(colors, &line, &mut diagnostics).0,
+                    });
+                }
+                _ => {}
+            }
+        }
+        geometry.end_step(&mut step_parts);
+        ParsedSource {
+            mesh: geometry,
+            header: parse_header(&source.path, source.line_offset, &source.text),
+            diagnostics,
+            references,
+        }
+    }
+}
+
+pub fn write_obj(geometry: &Geometry, filename: &str) -> io::Result<()> {
+    let output = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(filename)?;
+    let mut output = BufWriter::new(output);
+    // writeln!(output, "mtllib test.mtl")?;
+
+    let mesh = Mesh::new(geometry, DEFAULT_CREASE_ANGLE, [0.0, 1.0, 0.0, 1.0]);
+    for v in &mesh.positions {
+        writeln!(output, "v {} {} {}", v.x, -v.y, v.z)?;
+    }
+    for n in &mesh.normals {
+        writeln!(output, "vn {} {} {}", n.x, -n.y, n.z)?;
+    }
+
+    // writeln!(output, "g thing")?;
+    // writeln!(output, "usemtl red")?;
+
+    // writeln!(output, "s off");
+    for triangle in mesh.indices.chunks(3) {
+        write!(output, "f")?;
+        for i in triangle {
+            write!(output, " {}//{}", i + 1, i + 1)?;
+        }
+        writeln!(output)?;
+    }
+
+    let mut vertex_count = mesh.positions.len() + 1;
+    for e in &geometry.edges {
+        for v in &e.points {
+            writeln!(output, "v {} {} {}", v.x, -v.y, v.z)?;
+        }
+        writeln!(output, "l {} {}", vertex_count, vertex_count + 1)?;
+        vertex_count += 2;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::library::MemorySource;
+
+    fn parser<T: AsRef<[u8]>>(files: &[(&str, T)]) -> Parser {
+        let mut source = MemorySource::new();
+        for (name, data) in files {
+            source.insert(name, data.as_ref());
+        }
+        let mut library = LibraryConfig::empty();
+        library.add_source(source);
+        let mut parser = Parser::with_library(library);
+        parser.set_parallel(false);
+        parser
+    }
+
+    fn cycles(parser: &Parser) -> Vec<Vec<String>> {
+        parser
+            .diagnostics()
+            .iter()
+            .filter_map(|d| match &d.kind {
+                ParseErrorKind::Cycle(chain) => Some(chain.clone()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    const TRIANGLE: &str = "3 4 0 0 0 1 0 0 0 1 0\n";
+
+    #[test]
+    fn mpd_submodels() {
+        let mut parser = parser(&[(
+            "models/house.mpd",
+            "0 FILE main.ldr\n\
+             1 16 0 0 0 1 0 0 0 1 0 0 0 1 Wall Piece.ldr\n\
+             1 16 10 0 0 1 0 0 0 1 0 0 0 1 wall piece.ldr\n\
+             0 FILE Wall Piece.ldr\n\
+             3 16 0 0 0 1 0 0 0 1 0\n\
+             0 STEP\n\
+             3 16 0 0 0 1 0 0 0 1 0\n",
+        )]);
+        let geometry = parser.load("house.mpd").unwrap();
+        assert!(parser.diagnostics().is_empty(), "{:?}", parser.diagnostics());
+        assert_eq!(geometry.polygons.len(), 4);
+        assert_eq!(geometry.polygons[2].points[0], Point3::new(10.0, 0.0, 0.0));
+
+        let names: Vec<&str> = parser.submodels().iter().map(|s| s.name.as_str()).collect();
+        assert_eq!(names, ["main.ldr", "Wall Piece.ldr"]);
+        assert_eq!(parser.submodels()[1].line, 4);
+
+        let piece = parser.load_submodel("Wall Piece.ldr").unwrap();
+        assert_eq!(piece.steps.len(), 2);
+        assert_eq!(piece.up_to_step(0).polygons.len(), 1);
+    }
+
+    #[test]
+    fn mpd_submodels_come_before_library_files() {
+        let mut parser = parser(&[
+            ("parts/box.dat", "3 4 0 0 0 1 0 0 0 1 0\n3 4 0 0 0 1 0 0 0 1 0\n"),
+            ("parts/wrap.dat", "1 16 0 0 0 1 0 0 0 1 0 0 0 1 box.dat\n"),
+            (
+                "model.mpd",
+                "0 FILE main.ldr\n\
+                 1 16 0 0 0 1 0 0 0 1 0 0 0 1 box.dat\n\
+                 1 16 0 0 0 1 0 0 0 1 0 0 0 1 wrap.dat\n\
+                 0 FILE box.dat\n\
+                 3 4 0 0 0 1 0 0 0 1 0\n",
+            ),
+        ]);
+        // The library's wrap.dat still gets the library's box.dat.
+        assert_eq!(parser.load("model.mpd").unwrap().polygons.len(), 3);
+        assert_eq!(parser.load("box.dat").unwrap().polygons.len(), 2);
+    }
+
+    #[test]
+    fn library_parts_used_by_an_mpd_are_cached_as_library_parts() {
+        let mut parser = parser(&[
+            ("parts/box.dat", "3 4 0 0 0 1 0 0 0 1 0\n3 4 0 0 0 1 0 0 0 1 0\n"),
+            ("parts/wrap.dat", "1 16 0 0 0 1 0 0 0 1 0 0 0 1 box.dat\n"),
+            (
+                "model.mpd",
+                "0 FILE main.ldr\n1 16 0 0 0 1 0 0 0 1 0 0 0 1 wrap.dat\n0 FILE box.dat\n3 4 0 0 0 1 0 0 0 1 0\n",
+            ),
+        ]);
+        assert_eq!(parser.load("model.mpd").unwrap().polygons.len(), 2);
+        assert_eq!(parser.load("wrap.dat").unwrap().polygons.len(), 2);
+    }
+
+    #[test]
+    fn cycles_are_reported_and_broken() {
+        let files = [(
+            "cycle.mpd",
+            "0 FILE main.ldr\n\
+             1 16 0 0 0 1 0 0 0 1 0 0 0 1 a.ldr\n\
+             0 FILE a.ldr\n\
+             3 16 0 0 0 1 0 0 0 1 0\n\
+             1 16 0 0 0 1 0 0 0 1 0 0 0 1 B.ldr\n\
+             0 FILE b.ldr\n\
+             3 16 0 0 0 1 0 0 0 1 0\n\
+             1 16 0 0 0 1 0 0 0 1 0 0 0 1 a.ldr\n\
+             1 16 0 0 0 1 0 0 0 1 0 0 0 1 b.ldr\n",
+        )];
+        // Chains use the names the files were referenced by.
+        let expected = [vec!["a.ldr", "B.ldr", "a.ldr"], vec!["B.ldr", "b.ldr"]];
+
+        let mut flattened = parser(&files);
+        assert_eq!(flattened.load("cycle.mpd").unwrap().polygons.len(), 2);
+        assert_eq!(cycles(&flattened), expected);
+
+        let mut instanced = parser(&files);
+        let scene = instanced.load_scene("cycle.mpd").unwrap();
+        assert_eq!(scene.root.children.len(), 1);
+        assert_eq!(cycles(&instanced), expected);
+    }
+
+    #[test]
+    fn a_file_referencing_itself() {
+        let mut parser = parser(&[("parts/loop.dat", "3 4 0 0 0 1 0 0 0 1 0\n1 16 0 0 0 1 0 0 0 1 0 0 0 1 loop.dat\n")]);
+        assert_eq!(parser.load("loop.dat").unwrap().polygons.len(), 1);
+        assert_eq!(cycles(&parser), [vec!["loop.dat", "loop.dat"]]);
+    }
+
+    #[test]
+    fn nesting_is_limited() {
+        let mut parser = parser(&[
+            ("parts/d1.dat", "1 16 0 0 0 1 0 0 0 1 0 0 0 1 d2.dat\n"),
+            ("parts/d2.dat", "1 16 0 0 0 1 0 0 0 1 0 0 0 1 d3.dat\n"),
+            ("parts/d3.dat", TRIANGLE),
+        ]);
+        parser.set_max_depth(2);
+        assert!(parser.load("d1.dat").unwrap().polygons.is_empty());
+        assert_eq!(parser.diagnostics().len(), 1);
+        assert_eq!(parser.diagnostics()[0].kind, ParseErrorKind::TooDeep(2));
+        assert_eq!(parser.diagnostics()[0].token.as_deref(), Some("d3.dat"));
+
+        // d2.dat was only cut short because it was loaded under d1.dat.
+        assert_eq!(parser.load("d2.dat").unwrap().polygons.len(), 1);
+    }
+
+    #[test]
+    fn invalid_utf8_is_reported_where_it_is_parsed() {
+        let mut parser = parser(&[("parts/a.dat", b"0 Title\r0 Author: Jos\xe9\r3 4 0 0 0 1 0 0 0 1 0\r")]);
+        assert_eq!(parser.load("a.dat").unwrap().polygons.len(), 1);
+        assert_eq!(parser.header("a.dat").unwrap().author.as_deref(), Some("José"));
+        let diagnostic = &parser.diagnostics()[0];
+        assert_eq!(diagnostic.kind, ParseErrorKind::InvalidUtf8);
+        assert_eq!((diagnostic.line, diagnostic.column), (2, 14));
+    }
+
+    #[test]
+    fn ldconfig_is_read_as_latin_1_if_it_is_not_utf8() {
+        let parser = parser(&[(
+            "LDConfig.ldr",
+            &b"0 LDraw.org Configuration File\r\n0 !COLOUR Caf\xe9 CODE 4 VALUE #C91A09 EDGE #333333\r\n"[..],
+        )]);
+        assert_eq!(parser.colors().get(4).unwrap().name, "Café");
+        let diagnostic = &parser.diagnostics()[0];
+        assert_eq!(diagnostic.kind, ParseErrorKind::InvalidUtf8);
+        assert_eq!((diagnostic.path.as_path(), diagnostic.line, diagnostic.column), (Path::new("ldconfig.ldr"), 2, 14));
+    }
+
+    #[test]
+    fn missing_subfiles_are_reported_where_they_are_referenced() {
+        let mut parser = parser(&[("parts/a.dat", "3 4 0 0 0 1 0 0 0 1 0\n1 16 0 0 0 1 0 0 0 1 0 0 0 1 missing.dat\n")]);
+        assert_eq!(parser.load("a.dat").unwrap().polygons.len(), 1);
+        let diagnostic = &parser.diagnostics()[0];
+        assert_eq!(diagnostic.kind, ParseErrorKind::FileNotFound);
+        assert_eq!((diagnostic.line, diagnostic.token.as_deref()), (2, Some("missing.dat")));
+
+        assert_eq!(parser.load("missing.dat").unwrap_err().kind, ParseErrorKind::FileNotFound);
+    }
+
+    #[test]
+    fn moved_and_alias_parts_are_followed() {
+        let mut parser = parser(&[
+            ("parts/3001.dat", "0 Brick 2 x 4\n0 !LDRAW_ORG Part UPDATE 2004-01\n3 16 0 0 0 1 0 0 0 1 0\n"),
+            (
+                "parts/3001old.dat",
+                "0 ~Moved to 3001\n0 !LDRAW_ORG Part UPDATE 2010-01\n1 16 0 0 5 1 0 0 0 1 0 0 0 1 3001.dat\n",
+            ),
+            (
+                "parts/3001a.dat",
+                "0 =Brick 2 x 4\n0 !LDRAW_ORG Part Alias UPDATE 2010-01\n1 16 0 0 0 1 0 0 0 1 0 0 0 1 3001.dat\n",
+            ),
+            (
+                "model.ldr",
+                "1 4 10 0 0 1 0 0 0 1 0 0 0 1 3001old.dat\n1 2 0 0 0 1 0 0 0 1 0 0 0 1 3001a.dat\n",
+            ),
+        ]);
+        let geometry = parser.load("model.ldr").unwrap();
+        assert_eq!(geometry.polygons.len(), 2);
+        assert_eq!(geometry.polygons[0].points[0], Point3::new(10.0, 0.0, 5.0));
+
+        let redirects: Vec<_> = parser.redirects().iter().map(|r| (r.kind, r.from.as_str(), r.to.as_str())).collect();
+        assert_eq!(
+            redirects,
+            [(RedirectKind::Moved, "3001old.dat", "3001.dat"), (RedirectKind::Alias, "3001a.dat", "3001.dat")]
+        );
+        assert_eq!(parser.header("3001old.dat").unwrap().redirect_kind(), Some(RedirectKind::Moved));
+
+        let scene = parser.load_scene("model.ldr").unwrap();
+        let files: Vec<&str> = scene.root.children.iter().map(|c| c.file.as_str()).collect();
+        assert_eq!(files, ["parts/3001.dat", "parts/3001.dat"]);
+        assert_eq!(scene.root.children[0].transform.w.z, 5.0);
+    }
+
+    fn quad(points: [(f32, f32); 4]) -> [Point3<f32>; 4] {
+        points.map(|(x, y)| Point3::new(x, y, 0.0))
+    }
+
+    /// The z of each triangle's normal, which is positive for ones wound
+    /// counterclockwise when seen from +z, and twice its area.
+    fn windings(triangles: &[[Point3<f32>; 3]; 2]) -> [f32; 2] {
+        triangles.map(|[a, b, c]| (b - a).cross(c - b).z)
+    }
+
+    #[test]
+    fn convex_quads_split_along_a_c() {
+        let q = quad([(0.0, 0.0), (1.0, 0.0), (1.0, 1.0), (0.0, 1.0)]);
+        let (triangles, problem) = triangulate_quad(q);
+        assert_eq!(problem, None);
+        assert_eq!(triangles, [[q[0], q[1], q[2]], [q[2], q[3], q[0]]]);
+        assert_eq!(windings(&triangles), [1.0, 1.0]);
+
+        let mut clockwise = q;
+        clockwise.reverse();
+        let (triangles, problem) = triangulate_quad(clockwise);
+        assert_eq!(problem, None);
+        assert_eq!(windings(&triangles), [-1.0, -1.0]);
+    }
+
+    #[test]
+    fn warped_quads_are_reported() {
+        let mut q = quad([(0.0, 0.0), (1.0, 0.0), (1.0, 1.0), (0.0, 1.0)]);
+        q[3].z = 0.5;
+        let (triangles, problem) = triangulate_quad(q);
+        assert_eq!(problem.as_deref(), Some("quad isn't planar"));
+        assert_eq!(triangles, [[q[0], q[1], q[2]], [q[2], q[3], q[0]]]);
+    }
+
+    #[test]
+    fn concave_quads_split_through_the_reflex_corner() {
+        // A dart, counterclockwise, pointing up, with the reflex corner at
+        // (2, 1). Its area is 4.
+        let dart = [(0.0, 0.0), (2.0, 1.0), (4.0, 0.0), (2.0, 3.0)];
+
+        // Reflex corner at an odd index.
+        let q = quad(dart);
+        let (triangles, problem) = triangulate_quad(q);
+        assert_eq!(problem.as_deref(), Some("concave quad"));
+        assert_eq!(triangles, [[q[1], q[2], q[3]], [q[3], q[0], q[1]]]);
+        assert_eq!(windings(&triangles), [4.0, 4.0]);
+
+        // Reflex corner at an even index.
+        let q = quad([dart[1], dart[2], dart[3], dart[0]]);
+        let (triangles, problem) = triangulate_quad(q);
+        assert_eq!(problem.as_deref(), Some("concave quad"));
+        assert_eq!(triangles, [[q[0], q[1], q[2]], [q[2], q[3], q[0]]]);
+        assert_eq!(windings(&triangles), [4.0, 4.0]);
+
+        // Clockwise.
+        let q = quad([dart[3], dart[2], dart[1], dart[0]]);
+        let (triangles, _) = triangulate_quad(q);
+        assert_eq!(windings(&triangles), [-4.0, -4.0]);
+    }
+
+    #[test]
+    fn bow_ties_are_untangled_keeping_the_first_winding() {
+        // a, b, c turn counterclockwise, so d and c swap.
+        let q = quad([(0.0, 0.0), (1.0, 0.0), (0.0, 1.0), (1.0, 1.0)]);
+        let (triangles, problem) = triangulate_quad(q);
+        assert_eq!(problem.as_deref(), Some("bow-tie quad, drawn with its points reordered"));
+        assert_eq!(triangles, [[q[0], q[1], q[3]], [q[3], q[2], q[0]]]);
+        assert_eq!(windings(&triangles), [1.0, 1.0]);
+
+        // a, b, c turn clockwise, so b and d swap.
+        let q = quad([(0.0, 0.0), (1.0, 1.0), (1.0, 0.0), (0.0, 1.0)]);
+        let (triangles, problem) = triangulate_quad(q);
+        assert_eq!(problem.as_deref(), Some("bow-tie quad, drawn with its points reordered"));
+        assert_eq!(triangles, [[q[0], q[3], q[1]], [q[1], q[2], q[0]]]);
+        assert_eq!(windings(&triangles), [-1.0, -1.0]);
+    }
+
+    #[test]
+    fn quad_lines_keep_their_winding() {
+        let mut parser = parser(&[(
+            "parts/quads.dat",
+            "0 BFC CERTIFY CCW\n\
+             4 16 0 0 0 1 0 0 1 1 0 0 1 0\n\
+             4 16 0 0 0 1 0 0 0 1 0 1 1 0\n",
+        )]);
+        let geometry = parser.load("quads.dat").unwrap();
+        let windings: Vec<f32> = geometry
+            .polygons
+            .iter()
+            .map(|p| (p.points[1] - p.points[0]).cross(p.points[2] - p.points[1]).z)
+            .collect();
+        assert_eq!(windings, [1.0, 1.0, 1.0, 1.0]);
+        let diagnostic = &parser.diagnostics()[0];
+        assert_eq!((diagnostic.line, diagnostic.token.as_deref()), (3, None));
+    }
+}
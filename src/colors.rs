@@ -1,180 +0,0 @@
-"0" => RGBA(27, 42, 52, 0),
-"1" => RGBA(30, 90, 168, 0),
-"2" => RGBA(0, 133, 43, 0),
-"3" => RGBA(6, 157, 159, 0),
-"4" => RGBA(180, 0, 0, 0),
-"5" => RGBA(211, 53, 157, 0),
-"6" => RGBA(84, 51, 36, 0),
-"7" => RGBA(138, 146, 141, 0),
-"8" => RGBA(84, 89, 85, 0),
-"9" => RGBA(151, 203, 217, 0),
-"10" => RGBA(88, 171, 65, 0),
-"11" => RGBA(0, 170, 164, 0),
-"12" => RGBA(240, 109, 97, 0),
-"13" => RGBA(246, 169, 187, 0),
-"14" => RGBA(250, 200, 10, 0),
-"15" => RGBA(244, 244, 244, 0),
-"17" => RGBA(173, 217, 168, 0),
-"18" => RGBA(255, 214, 127, 0),
-"19" => RGBA(176, 160, 111, 0),
-"20" => RGBA(175, 190, 214, 0),
-"22" => RGBA(103, 31, 129, 0),
-"23" => RGBA(14, 62, 154, 0),
-"25" => RGBA(214, 121, 35, 0),
-"26" => RGBA(144, 31, 118, 0),
-"27" => RGBA(165, 202, 24, 0),
-"28" => RGBA(137, 125, 98, 0),
-"29" => RGBA(255, 158, 205, 0),
-"30" => RGBA(160, 110, 185, 0),
-"31" => RGBA(205, 164, 222, 0),
-"68" => RGBA(253, 195, 131, 0),
-"69" => RGBA(138, 18, 168, 0),
-"70" => RGBA(95, 49, 9, 0),
-"71" => RGBA(150, 150, 150, 0),
-"72" => RGBA(100, 100, 100, 0),
-"73" => RGBA(115, 150, 200, 0),
-"74" => RGBA(127, 196, 117, 0),
-"77" => RGBA(254, 204, 207, 0),
-"78" => RGBA(255, 201, 149, 0),
-"84" => RGBA(170, 125, 85, 0),
-"85" => RGBA(68, 26, 145, 0),
-"86" => RGBA(173, 97, 64, 0),
-"89" => RGBA(28, 88, 167, 0),
-"92" => RGBA(187, 128, 90, 0),
-"100" => RGBA(249, 183, 165, 0),
-"110" => RGBA(38, 70, 154, 0),
-"112" => RGBA(72, 97, 172, 0),
-"115" => RGBA(183, 212, 37, 0),
-"118" => RGBA(156, 214, 204, 0),
-"120" => RGBA(222, 234, 146, 0),
-"125" => RGBA(249, 167, 119, 0),
-"128" => RGBA(173, 97, 64, 0),
-"151" => RGBA(200, 200, 200, 0),
-"191" => RGBA(252, 172, 0, 0),
-"212" => RGBA(157, 195, 247, 0),
-"216" => RGBA(135, 43, 23, 0),
-"218" => RGBA(142, 85, 151, 0),
-"219" => RGBA(86, 78, 157, 0),
-"226" => RGBA(255, 236, 108, 0),
-"232" => RGBA(119, 201, 216, 0),
-"272" => RGBA(25, 50, 90, 0),
-"288" => RGBA(0, 69, 26, 0),
-"295" => RGBA(255, 148, 194, 0),
-"308" => RGBA(53, 33, 0, 0),
-"313" => RGBA(171, 217, 255, 0),
-"320" => RGBA(114, 0, 18, 0),
-"321" => RGBA(70, 155, 195, 0),
-"322" => RGBA(104, 195, 226, 0),
-"323" => RGBA(211, 242, 234, 0),
-"326" => RGBA(226, 249, 154, 0),
-"330" => RGBA(119, 119, 78, 0),
-"335" => RGBA(136, 96, 94, 0),
-"351" => RGBA(247, 133, 177, 0),
-"353" => RGBA(255, 109, 119, 0),
-"366" => RGBA(216, 109, 44, 0),
-"373" => RGBA(117, 101, 125, 0),
-"378" => RGBA(112, 142, 124, 0),
-"379" => RGBA(112, 129, 154, 0),
-"450" => RGBA(210, 119, 68, 0),
-"462" => RGBA(245, 134, 36, 0),
-"484" => RGBA(145, 80, 28, 0),
-"503" => RGBA(188, 180, 165, 0),
-"507" => RGBA(250, 156, 28, 0),
-"508" => RGBA(255, 128, 20, 0),
-"509" => RGBA(207, 138, 71, 0),
-"510" => RGBA(120, 252, 120, 0),
-"33" => RGBA(0, 32, 160, 128),
-"34" => RGBA(35, 120, 65, 128),
-"35" => RGBA(86, 230, 70, 128),
-"36" => RGBA(201, 26, 9, 128),
-"37" => RGBA(223, 102, 149, 128),
-"38" => RGBA(255, 128, 13, 128),
-"39" => RGBA(193, 223, 240, 128),
-"40" => RGBA(99, 95, 82, 128),
-"41" => RGBA(85, 154, 183, 128),
-"42" => RGBA(192, 255, 0, 128),
-"43" => RGBA(174, 233, 239, 128),
-"44" => RGBA(150, 112, 159, 128),
-"45" => RGBA(252, 151, 172, 128),
-"46" => RGBA(245, 205, 47, 128),
-"47" => RGBA(252, 252, 252, 128),
-"52" => RGBA(165, 165, 203, 128),
-"54" => RGBA(218, 176, 0, 128),
-"57" => RGBA(240, 143, 28, 128),
-"231" => RGBA(252, 183, 109, 128),
-"234" => RGBA(251, 232, 144, 128),
-"284" => RGBA(194, 129, 165, 128),
-"285" => RGBA(125, 194, 145, 128),
-"293" => RGBA(107, 171, 228, 128),
-"334" => RGBA(223, 193, 118, 0),
-"383" => RGBA(206, 206, 206, 0),
-"60" => RGBA(100, 90, 76, 0),
-"64" => RGBA(27, 42, 52, 0),
-"61" => RGBA(108, 150, 191, 0),
-"62" => RGBA(60, 179, 113, 0),
-"63" => RGBA(170, 77, 142, 0),
-"183" => RGBA(246, 242, 223, 0),
-"150" => RGBA(152, 155, 153, 0),
-"135" => RGBA(160, 160, 160, 0),
-"179" => RGBA(137, 135, 136, 0),
-"148" => RGBA(72, 77, 72, 0),
-"137" => RGBA(91, 117, 144, 0),
-"142" => RGBA(222, 172, 102, 0),
-"297" => RGBA(170, 127, 46, 0),
-"178" => RGBA(131, 114, 79, 0),
-"134" => RGBA(118, 77, 59, 0),
-"189" => RGBA(172, 130, 71, 0),
-"80" => RGBA(118, 118, 118, 0),
-"81" => RGBA(106, 121, 68, 0),
-"82" => RGBA(219, 172, 52, 0),
-"83" => RGBA(10, 19, 39, 0),
-"87" => RGBA(109, 110, 92, 0),
-"300" => RGBA(194, 127, 83, 0),
-"184" => RGBA(214, 0, 38, 0),
-"186" => RGBA(0, 142, 60, 0),
-"79" => RGBA(238, 238, 238, 240),
-"21" => RGBA(224, 255, 176, 240),
-"294" => RGBA(189, 198, 173, 240),
-"329" => RGBA(245, 243, 215, 240),
-"114" => RGBA(223, 102, 149, 128),
-"117" => RGBA(238, 238, 238, 128),
-"129" => RGBA(100, 0, 97, 128),
-"302" => RGBA(174, 233, 239, 128),
-"339" => RGBA(192, 255, 0, 128),
-"132" => RGBA(0, 0, 0, 0),
-"133" => RGBA(0, 0, 0, 0),
-"75" => RGBA(0, 0, 0, 0),
-"76" => RGBA(99, 95, 97, 0),
-"65" => RGBA(250, 200, 10, 0),
-"66" => RGBA(245, 205, 47, 128),
-"67" => RGBA(252, 252, 252, 128),
-"256" => RGBA(27, 42, 52, 0),
-"273" => RGBA(30, 90, 168, 0),
-"324" => RGBA(180, 0, 0, 0),
-"350" => RGBA(214, 121, 35, 0),
-"375" => RGBA(138, 146, 141, 0),
-"406" => RGBA(25, 50, 90, 0),
-"449" => RGBA(103, 31, 129, 0),
-"490" => RGBA(165, 202, 24, 0),
-"496" => RGBA(150, 150, 150, 0),
-"504" => RGBA(137, 135, 136, 0),
-"511" => RGBA(244, 244, 244, 0),
-"10002" => RGBA(88, 171, 65, 0),
-"10026" => RGBA(144, 31, 118, 0),
-"10030" => RGBA(160, 110, 185, 0),
-"10031" => RGBA(205, 164, 222, 0),
-"10070" => RGBA(95, 49, 9, 0),
-"10226" => RGBA(255, 236, 108, 0),
-"10308" => RGBA(53, 33, 0, 0),
-"10320" => RGBA(114, 0, 18, 0),
-"10321" => RGBA(70, 155, 195, 0),
-"10322" => RGBA(104, 195, 226, 0),
-"10323" => RGBA(211, 242, 234, 0),
-"10484" => RGBA(145, 80, 28, 0),
-"16" => RGBA(255, 255, 128, 0),
-"24" => RGBA(127, 127, 127, 0),
-"32" => RGBA(0, 0, 0, 210),
-"493" => RGBA(101, 103, 97, 0),
-"494" => RGBA(208, 208, 208, 0),
-"495" => RGBA(174, 122, 89, 0),
-"10047" => RGBA(255, 255, 255, 16),
@@ -5,6 +5,7 @@ use glutin::dpi::PhysicalSize;
 use glutin::window::Window;
 use glutin::ContextBuilder;
 use glutin::event_loop::EventLoop;
+use std::collections::HashMap;
 use std::ffi::CString;
 use std::{ptr, mem};
 use cgmath::{Matrix4, Vector2, Deg, Vector3, Point3, SquareMatrix, Vector4};
@@ -100,6 +101,85 @@ void main() {
 }
 \0";
 
+const VS_SRC_LINES: &[u8] = b"
+#version 330 core
+
+layout (location = 0) in vec3 position;
+layout (location = 1) in vec4 color;
+
+uniform mat4 world;
+uniform mat4 view;
+uniform mat4 proj;
+
+out vec4 v_color;
+
+void main() {
+    v_color = color;
+    gl_Position = proj * view * world * vec4(position, 1.0);
+}
+\0";
+
+const FS_SRC_LINES: &[u8] = b"
+#version 330 core
+
+in vec4 v_color;
+
+void main() {
+    gl_FragColor = v_color;
+}
+\0";
+
+// Conditional lines carry the other end of the line and both control points
+// on every vertex, so each vertex can decide whether the line is visible.
+const VS_SRC_CONDITIONAL_LINES: &[u8] = b"
+#version 330 core
+
+layout (location = 0) in vec3 position;
+layout (location = 1) in vec3 other;
+layout (location = 2) in vec3 control1;
+layout (location = 3) in vec3 control2;
+layout (location = 4) in vec4 color;
+
+uniform mat4 world;
+uniform mat4 view;
+uniform mat4 proj;
+
+out vec4 v_color;
+out float v_visible;
+
+vec2 project(mat4 m, vec3 p) {
+    vec4 clip = m * vec4(p, 1.0);
+    return clip.xy / clip.w;
+}
+
+void main() {
+    mat4 m = proj * view * world;
+    vec2 a = project(m, position);
+    vec2 d = project(m, other) - a;
+    vec2 c1 = project(m, control1) - a;
+    vec2 c2 = project(m, control2) - a;
+    float side1 = d.x * c1.y - d.y * c1.x;
+    float side2 = d.x * c2.y - d.y * c2.x;
+    v_visible = side1 * side2 >= 0.0 ? 1.0 : 0.0;
+    v_color = color;
+    gl_Position = m * vec4(position, 1.0);
+}
+\0";
+
+const FS_SRC_CONDITIONAL_LINES: &[u8] = b"
+#version 330 core
+
+in vec4 v_color;
+in float v_visible;
+
+void main() {
+    if (v_visible < 0.5) {
+        discard;
+    }
+    gl_FragColor = v_color;
+}
+\0";
+
 const VS_SRC_TEXT: &[u8] = b"
 #version 330 core
 
@@ -195,9 +275,17 @@ impl Camera {
     }
 }
 
+#[derive(Clone)]
 pub struct Model {
     pub vao: u32,
-    pub vertex_buffer_length: i32,
+    pub index_count: i32,
+    /// The number of indices at the start of the index buffer that belong
+    /// to BFC-certified polygons and can be back-face culled.
+    pub culled_index_count: i32,
+    pub edge_vao: u32,
+    pub edge_buffer_length: i32,
+    pub conditional_edge_vao: u32,
+    pub conditional_edge_buffer_length: i32,
     pub position: Vector3<i32>,
     pub rotation: Vector3<i32>,
     pub transform: Matrix4<f32>,
@@ -216,6 +304,7 @@ impl Model {
     }
 }
 
+#[derive(Clone)]
 pub struct BoundingBox {
     pub min: Point3<f32>,
     pub max: Point3<f32>,
@@ -233,6 +322,12 @@ pub struct Uniforms {
     light_specular: GLint,
 }
 
+pub struct LineUniforms {
+    world: GLint,
+    view: GLint,
+    proj: GLint,
+}
+
 fn unproject(source: Vector3<f32>, view: Matrix4<f32>, proj: Matrix4<f32>) -> Vector3<f32> {
     let view_proj = (proj * view).invert().unwrap();
     let q = view_proj * Vector4::new(source.x, source.y, source.z, 1.0);
@@ -248,6 +343,7 @@ fn get_mouse_ray(aspect_ratio: f32, mouse_position: Vector2<f32>, camera: &Camer
     (camera.position(), direction)
 }
 
+#[allow(clippy::all)]
 pub mod gl {
     pub use self::Gles2 as Gl;
     include!(concat!(env!("OUT_DIR"), "/gl_bindings.rs"));
@@ -261,9 +357,17 @@ pub struct Graphics {
     program_2d: u32,
     program_text: u32,
     program_texture: u32,
+    program_lines: u32,
+    program_conditional_lines: u32,
     pub gl: gl::Gl,
     uniforms: Uniforms,
+    line_uniforms: LineUniforms,
+    conditional_line_uniforms: LineUniforms,
     font: rusttype::Font<'static>,
+    pub show_edges: bool,
+    /// The buffers behind each VAO that has been loaded, so that they can be
+    /// deleted along with it.
+    buffers: HashMap<GLuint, Vec<GLuint>>,
 }
 
 fn create_shader(gl: &gl::Gl, shader_type: u32, source: &'static [u8]) -> u32 {
@@ -359,6 +463,8 @@ pub fn init(
     let program_2d = create_program(&gl, VS_SRC_2D, FS_SRC_2D);
     let program_text = create_program(&gl, VS_SRC_TEXT, FS_SRC_TEXT);
     let program_texture = create_program(&gl, VS_SRC_2D_TEXTURE, FS_SRC_2D_TEXTURE);
+    let program_lines = create_program(&gl, VS_SRC_LINES, FS_SRC_LINES);
+    let program_conditional_lines = create_program(&gl, VS_SRC_CONDITIONAL_LINES, FS_SRC_CONDITIONAL_LINES);
 
     let uniforms = unsafe {
         Uniforms {
@@ -373,6 +479,20 @@ pub fn init(
             light_specular: gl.GetUniformLocation(program, b"light.specular\0".as_ptr() as *const _),
         }
     };
+    let line_uniforms = unsafe {
+        LineUniforms {
+            world: gl.GetUniformLocation(program_lines, b"world\0".as_ptr() as *const _),
+            view: gl.GetUniformLocation(program_lines, b"view\0".as_ptr() as *const _),
+            proj: gl.GetUniformLocation(program_lines, b"proj\0".as_ptr() as *const _),
+        }
+    };
+    let conditional_line_uniforms = unsafe {
+        LineUniforms {
+            world: gl.GetUniformLocation(program_conditional_lines, b"world\0".as_ptr() as *const _),
+            view: gl.GetUniformLocation(program_conditional_lines, b"view\0".as_ptr() as *const _),
+            proj: gl.GetUniformLocation(program_conditional_lines, b"proj\0".as_ptr() as *const _),
+        }
+    };
     Graphics {
         windowed_context,
         window_height,
@@ -381,9 +501,15 @@ pub fn init(
         program_2d,
         program_text,
         program_texture,
+        program_lines,
+        program_conditional_lines,
         gl,
         uniforms,
+        line_uniforms,
+        conditional_line_uniforms,
         font,
+        show_edges: true,
+        buffers: HashMap::new(),
     }
 }
 
@@ -408,7 +534,7 @@ impl Graphics {
         let x = rect.x as f32 * 2.0 / self.window_width as f32 - 1.0;
         let y = 1.0 - rect.y as f32 * 2.0 / self.window_height as f32;
         let width = rect.width as f32 * 2.0 / self.window_width as f32;
-        let height = -1.0 * rect.height as f32 * 2.0 / self.window_height as f32;
+        let height = -(rect.height as f32) * 2.0 / self.window_height as f32;
         let color = [
             color.r as f32 / 255.0,
             color.g as f32 / 255.0,
@@ -433,7 +559,7 @@ impl Graphics {
             gl.BindBuffer(gl::ARRAY_BUFFER, vbo_2d);
             gl.BufferData(
                 gl::ARRAY_BUFFER,
-                (vertices.len() * mem::size_of::<GLfloat>()) as GLsizeiptr,
+                mem::size_of_val(&vertices) as GLsizeiptr,
                 vertices.as_ptr() as *const _,
                 gl::STATIC_DRAW
             );
@@ -453,8 +579,8 @@ impl Graphics {
         }
 
         unsafe {
-            gl.DeleteVertexArrays(1, &mut vao_2d);
-            gl.DeleteBuffers(1, &mut vbo_2d);
+            gl.DeleteVertexArrays(1, &vao_2d);
+            gl.DeleteBuffers(1, &vbo_2d);
         }
     }
 
@@ -519,18 +645,20 @@ impl Graphics {
     //     self.draw_model(&vertices, world, view, proj, view_position, light);
     // }
 
-    pub fn load_model(&mut self, vertices: &[f32]) -> (u32, i32) {
-        // TODO there is no "unload_model" right now because this is meant to be run once for each
-        // model, and all the memory can be cleaned up when the program exits.
+    /// Uploads triangles, given as position (3), normal (3) and color (4)
+    /// per vertex and three indices into them per triangle. Returns the VAO
+    /// and the number of indices.
+    pub fn load_model(&mut self, vertices: &[f32], indices: &[u32]) -> (u32, i32) {
         let gl = &self.gl;
-        let (mut vao, mut vbo) = (0, 0);
+        let (mut vao, mut vbo, mut ebo) = (0, 0, 0);
         unsafe {
             gl.GenVertexArrays(1, &mut vao);
             gl.GenBuffers(1, &mut vbo);
+            gl.GenBuffers(1, &mut ebo);
             gl.BindBuffer(gl::ARRAY_BUFFER, vbo);
             gl.BufferData(
                 gl::ARRAY_BUFFER,
-                (vertices.len() * mem::size_of::<GLfloat>()) as GLsizeiptr,
+                mem::size_of_val(vertices) as GLsizeiptr,
                 vertices.as_ptr() as *const _,
                 gl::STATIC_DRAW
             );
@@ -542,10 +670,91 @@ impl Graphics {
             gl.VertexAttribPointer(1, 3, gl::FLOAT, gl::FALSE, stride, (3 * mem::size_of::<GLfloat>()) as *const _);
             gl.EnableVertexAttribArray(2);
             gl.VertexAttribPointer(2, 4, gl::FLOAT, gl::FALSE, stride, (6 * mem::size_of::<GLfloat>()) as *const _);
+            // The index buffer binding is part of the VAO, so it stays bound.
+            gl.BindBuffer(gl::ELEMENT_ARRAY_BUFFER, ebo);
+            gl.BufferData(
+                gl::ELEMENT_ARRAY_BUFFER,
+                mem::size_of_val(indices) as GLsizeiptr,
+                indices.as_ptr() as *const _,
+                gl::STATIC_DRAW
+            );
             gl.BindBuffer(gl::ARRAY_BUFFER, 0);
             gl.BindVertexArray(0);
         }
-        (vao, vertices.len() as i32)
+        self.buffers.insert(vao, vec![vbo, ebo]);
+        (vao, indices.len() as i32)
+    }
+
+    /// Uploads edge lines, given as position (3) and color (4) per vertex.
+    /// Returns the VAO and the number of vertices.
+    pub fn load_lines(&mut self, vertices: &[f32]) -> (u32, i32) {
+        let gl = &self.gl;
+        let (mut vao, mut vbo) = (0, 0);
+        unsafe {
+            gl.GenVertexArrays(1, &mut vao);
+            gl.GenBuffers(1, &mut vbo);
+            gl.BindBuffer(gl::ARRAY_BUFFER, vbo);
+            gl.BufferData(
+                gl::ARRAY_BUFFER,
+                mem::size_of_val(vertices) as GLsizeiptr,
+                vertices.as_ptr() as *const _,
+                gl::STATIC_DRAW
+            );
+            gl.BindVertexArray(vao);
+            let stride = 7 * mem::size_of::<GLfloat>() as GLsizei;
+            gl.EnableVertexAttribArray(0);
+            gl.VertexAttribPointer(0, 3, gl::FLOAT, gl::FALSE, stride, ptr::null());
+            gl.EnableVertexAttribArray(1);
+            gl.VertexAttribPointer(1, 4, gl::FLOAT, gl::FALSE, stride, (3 * mem::size_of::<GLfloat>()) as *const _);
+            gl.BindBuffer(gl::ARRAY_BUFFER, 0);
+            gl.BindVertexArray(0);
+        }
+        self.buffers.insert(vao, vec![vbo]);
+        (vao, (vertices.len() / 7) as i32)
+    }
+
+    /// Uploads conditional lines, given as position (3), the other end of
+    /// the line (3), both control points (3 each) and color (4) per vertex.
+    /// Returns the VAO and the number of vertices.
+    pub fn load_conditional_lines(&mut self, vertices: &[f32]) -> (u32, i32) {
+        let gl = &self.gl;
+        let (mut vao, mut vbo) = (0, 0);
+        unsafe {
+            gl.GenVertexArrays(1, &mut vao);
+            gl.GenBuffers(1, &mut vbo);
+            gl.BindBuffer(gl::ARRAY_BUFFER, vbo);
+            gl.BufferData(
+                gl::ARRAY_BUFFER,
+                mem::size_of_val(vertices) as GLsizeiptr,
+                vertices.as_ptr() as *const _,
+                gl::STATIC_DRAW
+            );
+            gl.BindVertexArray(vao);
+            let stride = 16 * mem::size_of::<GLfloat>() as GLsizei;
+            for i in 0..4 {
+                gl.EnableVertexAttribArray(i);
+                gl.VertexAttribPointer(i, 3, gl::FLOAT, gl::FALSE, stride, (3 * i as usize * mem::size_of::<GLfloat>()) as *const _);
+            }
+            gl.EnableVertexAttribArray(4);
+            gl.VertexAttribPointer(4, 4, gl::FLOAT, gl::FALSE, stride, (12 * mem::size_of::<GLfloat>()) as *const _);
+            gl.BindBuffer(gl::ARRAY_BUFFER, 0);
+            gl.BindVertexArray(0);
+        }
+        self.buffers.insert(vao, vec![vbo]);
+        (vao, (vertices.len() / 16) as i32)
+    }
+
+    /// Deletes the VAOs and buffers of a model. Clones of the model share
+    /// them, so none of its clones can be drawn afterwards either.
+    pub fn unload_model(&mut self, model: &Model) {
+        let gl = &self.gl;
+        for vao in &[model.vao, model.edge_vao, model.conditional_edge_vao] {
+            let buffers = self.buffers.remove(vao).unwrap_or_default();
+            unsafe {
+                gl.DeleteBuffers(buffers.len() as GLsizei, buffers.as_ptr());
+                gl.DeleteVertexArrays(1, vao);
+            }
+        }
     }
 
     pub fn start_3d(&self) {
@@ -554,12 +763,12 @@ impl Graphics {
         }
     }
 
-    pub fn draw_model(&self, vao: GLuint, vertex_buffer_length: i32, world: [f32; 16], view: [f32; 16], proj: [f32; 16], view_position: [f32; 3], light: [f32; 15]) {
+    pub fn draw_model(&self, model: &Model, world: [f32; 16], view: [f32; 16], proj: [f32; 16], view_position: [f32; 3], light: [f32; 15]) {
         let gl = &self.gl;
         unsafe {
             gl.Enable(gl::DEPTH_TEST);
             gl.BlendFunc(gl::ONE, gl::ONE_MINUS_SRC_ALPHA);
-            // gl.UseProgram(self.program);
+            gl.UseProgram(self.program);
 
             gl.UniformMatrix4fv(self.uniforms.world, 1, gl::FALSE, world.as_ptr());
             gl.UniformMatrix4fv(self.uniforms.view, 1, gl::FALSE, view.as_ptr());
@@ -571,8 +780,41 @@ impl Graphics {
             gl.Uniform3f(self.uniforms.light_diffuse, light[9], light[10], light[11]);
             gl.Uniform3f(self.uniforms.light_specular, light[12], light[13], light[14]);
 
-            gl.BindVertexArray(vao);
-            gl.DrawArrays(gl::TRIANGLES, 0, vertex_buffer_length as GLsizei);
+            // Push the faces back a little so that the edges drawn on top of
+            // them don't z-fight.
+            gl.Enable(gl::POLYGON_OFFSET_FILL);
+            gl.PolygonOffset(1.0, 1.0);
+            gl.BindVertexArray(model.vao);
+            // Models are mirrored in y when they're loaded, which turns
+            // LDraw's counter-clockwise front faces clockwise.
+            gl.Enable(gl::CULL_FACE);
+            gl.FrontFace(gl::CW);
+            gl.DrawElements(gl::TRIANGLES, model.culled_index_count as GLsizei, gl::UNSIGNED_INT, ptr::null());
+            gl.Disable(gl::CULL_FACE);
+            gl.DrawElements(
+                gl::TRIANGLES,
+                (model.index_count - model.culled_index_count) as GLsizei,
+                gl::UNSIGNED_INT,
+                (model.culled_index_count as usize * mem::size_of::<u32>()) as *const _,
+            );
+            gl.Disable(gl::POLYGON_OFFSET_FILL);
+
+            if self.show_edges {
+                for (program, uniforms, vao, length) in [
+                    (self.program_lines, &self.line_uniforms, model.edge_vao, model.edge_buffer_length),
+                    (self.program_conditional_lines, &self.conditional_line_uniforms, model.conditional_edge_vao, model.conditional_edge_buffer_length),
+                ] {
+                    if length == 0 {
+                        continue;
+                    }
+                    gl.UseProgram(program);
+                    gl.UniformMatrix4fv(uniforms.world, 1, gl::FALSE, world.as_ptr());
+                    gl.UniformMatrix4fv(uniforms.view, 1, gl::FALSE, view.as_ptr());
+                    gl.UniformMatrix4fv(uniforms.proj, 1, gl::FALSE, proj.as_ptr());
+                    gl.BindVertexArray(vao);
+                    gl.DrawArrays(gl::LINES, 0, length as GLsizei);
+                }
+            }
             // gl.BindVertexArray(0);
             gl.Disable(gl::DEPTH_TEST);
         }
@@ -587,7 +829,7 @@ impl Graphics {
         let src_height = src_rect.height as usize;
 
         // Load the texture from the buffer
-        let (uniform, mut id) = unsafe {
+        let (uniform, id) = unsafe {
             gl.BlendFunc(gl::SRC_ALPHA, gl::ONE_MINUS_SRC_ALPHA);
             gl.Disable(gl::DEPTH_TEST);
 
@@ -638,7 +880,7 @@ impl Graphics {
             gl.BindBuffer(gl::ARRAY_BUFFER, vbo);
             gl.BufferData(
                 gl::ARRAY_BUFFER,
-                (vertices.len() * mem::size_of::<GLfloat>()) as GLsizeiptr,
+                mem::size_of_val(&vertices) as GLsizeiptr,
                 vertices.as_ptr() as *const _,
                 gl::STATIC_DRAW
             );
@@ -663,9 +905,9 @@ impl Graphics {
         }
 
         unsafe {
-            gl.DeleteBuffers(1, &mut vbo);
-            gl.DeleteVertexArrays(1, &mut vao);
-            gl.DeleteTextures(1, &mut id);
+            gl.DeleteBuffers(1, &vbo);
+            gl.DeleteVertexArrays(1, &vao);
+            gl.DeleteTextures(1, &id);
         }
     }
 
@@ -680,7 +922,7 @@ impl Graphics {
         let width = glyphs
             .iter()
             .rev()
-            .map(|g| g.position().x as f32 + g.unpositioned().h_metrics().advance_width)
+            .map(|g| g.position().x + g.unpositioned().h_metrics().advance_width)
             .next()
             .unwrap_or(0.0)
             .ceil() as usize;
@@ -714,7 +956,7 @@ impl Graphics {
         }
 
         // Load the texture from the buffer
-        let (uniform, mut id) = unsafe {
+        let (uniform, id) = unsafe {
             gl.BlendFunc(gl::SRC_ALPHA, gl::ONE_MINUS_SRC_ALPHA);
             gl.Disable(gl::DEPTH_TEST);
 
@@ -771,7 +1013,7 @@ impl Graphics {
             gl.BindBuffer(gl::ARRAY_BUFFER, vbo);
             gl.BufferData(
                 gl::ARRAY_BUFFER,
-                (vertices.len() * mem::size_of::<GLfloat>()) as GLsizeiptr,
+                mem::size_of_val(&vertices) as GLsizeiptr,
                 vertices.as_ptr() as *const _,
                 gl::STATIC_DRAW
             );
@@ -798,9 +1040,9 @@ impl Graphics {
         }
 
         unsafe {
-            gl.DeleteBuffers(1, &mut vbo);
-            gl.DeleteVertexArrays(1, &mut vao);
-            gl.DeleteTextures(1, &mut id);
+            gl.DeleteBuffers(1, &vbo);
+            gl.DeleteVertexArrays(1, &vao);
+            gl.DeleteTextures(1, &id);
         }
 
         Rect::new(input_x, input_y, glyphs_width as u32, glyphs_height as u32)
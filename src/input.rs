@@ -112,12 +112,12 @@ impl InputState {
     pub fn get_scroll_delta_x(&mut self) -> i32 {
         let delta = self.scroll_delta_x;
         self.scroll_delta_x = 0;
-        return delta;
+        delta
     }
     pub fn get_scroll_delta_y(&mut self) -> i32 {
         let delta = self.scroll_delta_y;
         self.scroll_delta_y = 0;
-        return delta;
+        delta
     }
     pub fn key_down(&self, key: VirtualKeyCode) -> bool {
         self.keys_down.contains_key(&key)
@@ -1,16 +1,18 @@
 use glutin::event::{Event, WindowEvent, ElementState, MouseScrollDelta};
 use glutin::event::VirtualKeyCode as Key;
 use glutin::event_loop::{ControlFlow, EventLoop};
-use glutin::window::WindowBuilder;
-use glutin::ContextBuilder;
 use cgmath::{Matrix4, Deg, Vector3, Point3, SquareMatrix};
+use std::sync::Arc;
 use std::time::Instant;
 
 mod graphics;
 use graphics::{BoundingBox, Camera, Graphics, Model};
 
-mod parser;
-use parser::Parser;
+use ldraw::{cache, normals, parser};
+use ldraw::catalog::{Catalog, CatalogEntry};
+use ldraw::library::LibraryConfig;
+use ldraw::mesh::Mesh;
+use ldraw::parser::Parser;
 
 mod input;
 use input::InputState;
@@ -30,6 +32,12 @@ struct State {
     camera: Camera,
     aspect_ratio: f32,
     active_model_idx: usize,
+    /// The submodel of the document that is shown, and the step it is shown
+    /// up to.
+    submodel_idx: usize,
+    step: usize,
+    /// The part in the palette that T adds.
+    palette_idx: usize,
 }
 
 impl State {
@@ -38,6 +46,9 @@ impl State {
             aspect_ratio: 1.0,
             camera: Camera::new(),
             active_model_idx: 0,
+            submodel_idx: 0,
+            step: 0,
+            palette_idx: 0,
         }
     }
 }
@@ -62,53 +73,103 @@ fn mat_to_array(m: Matrix4<f32>) -> [f32; 16] {
     ]
 }
 
+/// Prints the problems the parser has found since the last call.
+fn print_diagnostics(parser: &mut Parser) {
+    for diagnostic in parser.take_diagnostics() {
+        println!("WARNING: {}. Skipping...", diagnostic);
+    }
+}
+
 fn load_ldraw_file(gl: &mut Graphics, parser: &mut Parser, filename: &str, custom_color: Option<[f32; 4]>) -> Model {
-    let polygons = parser.load(filename);
-    let mut vertices = Vec::new();
+    let geometry = parser.load(filename).unwrap_or_else(|e| {
+        eprintln!("ERROR: {}", e);
+        Default::default()
+    });
+    print_diagnostics(parser);
+    load_geometry(gl, &geometry, custom_color)
+}
+
+/// Replaces the model shown for a document with the given step of one of its
+/// submodels, freeing the buffers of the previous one.
+fn show_step(gl: &mut Graphics, model: &mut Option<Model>, geometry: &parser::Geometry, step: usize) {
+    if let Some(model) = model.take() {
+        gl.unload_model(&model);
+    }
+    *model = Some(load_geometry(gl, &geometry.up_to_step(step), None));
+}
+
+fn load_geometry(gl: &mut Graphics, geometry: &parser::Geometry, custom_color: Option<[f32; 4]>) -> Model {
+    let mut mesh = Mesh::new(geometry, normals::DEFAULT_CREASE_ANGLE, [0.0, 1.0, 0.0, 1.0]);
+    if let Some(c) = custom_color {
+        mesh.colors.iter_mut().for_each(|color| *color = c);
+    }
+    let mut vertices = Vec::with_capacity(mesh.positions.len() * 10);
     let mut bounding_box = BoundingBox {
         min: Point3::new(f32::MAX, f32::MAX, f32::MAX),
         max: Point3::new(f32::MIN, f32::MIN, f32::MIN),
     };
-    for polygon in &polygons {
-        let mut color = match polygon.color {
+    for ((point, n), color) in mesh.positions.iter().zip(&mesh.normals).zip(&mesh.colors) {
+        vertices.push(point.x / 40.0);
+        vertices.push(point.y / -40.0);
+        vertices.push(point.z / 40.0);
+        vertices.push(n.x);
+        vertices.push(n.y);
+        vertices.push(n.z);
+        // TODO Might have to sort transparent faces
+        vertices.extend_from_slice(color);
+
+        bounding_box.min.x = fmin(bounding_box.min.x, point.x / 40.0);
+        bounding_box.min.y = fmin(bounding_box.min.y, point.y / -40.0);
+        bounding_box.min.z = fmin(bounding_box.min.z, point.z / 40.0);
+        bounding_box.max.x = fmax(bounding_box.max.x, point.x / 40.0);
+        bounding_box.max.y = fmax(bounding_box.max.y, point.y / -40.0);
+        bounding_box.max.z = fmax(bounding_box.max.z, point.z / 40.0);
+    }
+
+    let mut edge_vertices = Vec::new();
+    for edge in &geometry.edges {
+        let color = match edge.color {
             parser::LdrawColor::RGBA(r, g, b, a) => [r, g, b, a],
-            _ => [0.0, 1.0, 0.0, 1.0],
+            _ => [0.0, 0.0, 0.0, 1.0],
         };
-        if let Some(c) = custom_color {
-            color = c;
+        for point in &edge.points {
+            edge_vertices.push(point.x / 40.0);
+            edge_vertices.push(point.y / -40.0);
+            edge_vertices.push(point.z / 40.0);
+            edge_vertices.extend_from_slice(&color);
         }
+    }
 
-        if polygon.points.len() == 3 {
-            let n = parser::norm(polygon);
-            for point in &polygon.points {
-
-                vertices.push(point.x / 40.0);
-                vertices.push(point.y / -40.0);
-                vertices.push(point.z / 40.0);
-                vertices.push(n.x);
-                vertices.push(n.y);
-                vertices.push(n.z);
-                // TODO Might have to sort transparent faces
-                vertices.push(color[0]);
-                vertices.push(color[1]);
-                vertices.push(color[2]);
-                vertices.push(color[3]);
-
-                bounding_box.min.x = fmin(bounding_box.min.x, point.x / 40.0);
-                bounding_box.min.y = fmin(bounding_box.min.y, point.y / -40.0);
-                bounding_box.min.z = fmin(bounding_box.min.z, point.z / 40.0);
-                bounding_box.max.x = fmax(bounding_box.max.x, point.x / 40.0);
-                bounding_box.max.y = fmax(bounding_box.max.y, point.y / -40.0);
-                bounding_box.max.z = fmax(bounding_box.max.z, point.z / 40.0);
+    let mut conditional_edge_vertices = Vec::new();
+    for edge in &geometry.conditional_edges {
+        let color = match edge.color {
+            parser::LdrawColor::RGBA(r, g, b, a) => [r, g, b, a],
+            _ => [0.0, 0.0, 0.0, 1.0],
+        };
+        let [a, b] = edge.points;
+        let [c1, c2] = edge.controls;
+        for (point, other) in &[(a, b), (b, a)] {
+            for p in &[point, other, &c1, &c2] {
+                conditional_edge_vertices.push(p.x / 40.0);
+                conditional_edge_vertices.push(p.y / -40.0);
+                conditional_edge_vertices.push(p.z / 40.0);
             }
+            conditional_edge_vertices.extend_from_slice(&color);
         }
     }
 
-    let (vao, vertex_buffer_length) = gl.load_model(&vertices);
+    let (vao, index_count) = gl.load_model(&vertices, &mesh.indices);
+    let (edge_vao, edge_buffer_length) = gl.load_lines(&edge_vertices);
+    let (conditional_edge_vao, conditional_edge_buffer_length) = gl.load_conditional_lines(&conditional_edge_vertices);
 
     Model {
         vao,
-        vertex_buffer_length,
+        index_count,
+        culled_index_count: mesh.culled_index_count as i32,
+        edge_vao,
+        edge_buffer_length,
+        conditional_edge_vao,
+        conditional_edge_buffer_length,
         position: Vector3::new(0, 0, 0),
         rotation: Vector3::new(0, 0, 0),
         transform: Matrix4::identity(),
@@ -120,11 +181,29 @@ fn load_ldraw_file(gl: &mut Graphics, parser: &mut Parser, filename: &str, custo
 
 fn main() {
 
-    let mut parser = Parser::new("/home/paul/Downloads/ldraw");
+    let library = LibraryConfig::from_env()
+        .unwrap_or_else(|| LibraryConfig::new("/home/paul/Downloads/ldraw"))
+        .unwrap_or_else(|e| {
+            println!("WARNING: couldn't open the library: {}", e);
+            LibraryConfig::empty()
+        });
+    let mut parser = Parser::with_library(library);
+    parser.set_disk_cache(Some(cache::DiskCache::new(std::env::temp_dir().join("ld_glutin-cache"))));
+    // The bricks in the library, which [ and ] choose between.
+    let catalog_path = std::env::temp_dir().join("ld_glutin-catalog.txt");
+    let (catalog, saved) = Catalog::open(parser.library(), &catalog_path);
+    if let Err(e) = saved {
+        println!("WARNING: couldn't save the part catalog to {}: {}", catalog_path.display(), e);
+    }
+    let mut palette: Vec<CatalogEntry> = catalog.search("category:brick").into_iter().cloned().collect();
+    if palette.is_empty() {
+        palette.extend(catalog.get("3005.dat").cloned());
+    }
     let event_loop = EventLoop::new();
     let mut graphics = graphics::init(&event_loop);
 
     let mut state = State::new();
+    state.palette_idx = palette.iter().position(|entry| entry.name == "3005.dat").unwrap_or(0);
     let mut input = InputState::new();
     let mut models = Vec::new();
 
@@ -133,16 +212,13 @@ fn main() {
     let mut new_position = Vector3::new(0, 0, 0);
     // // models.push(load_ldraw_file(ldraw_dir, "car.ldr", None));
 
-    println!(
-        "load time: {} ms",
-        start.elapsed().as_millis(),
-    );
-
     let baseplate = load_ldraw_file(&mut graphics, &mut parser, "3811.dat", None);
+    // Every brick shares the same buffers and only has its own transform.
+    let brick = load_ldraw_file(&mut graphics, &mut parser, "3005.dat", Some([1.0, 0.0, 0.0, 0.5]));
     for x in 0..20 {
         for y in 0..20 {
             for z in 0..20 {
-                let mut model = load_ldraw_file(&mut graphics, &mut parser, "3005.dat", Some([1.0, 0.0, 0.0, 0.5]));
+                let mut model = brick.clone();
                 model.position = new_position;
                 new_position.x = x;
                 new_position.y = y * 3;
@@ -153,6 +229,43 @@ fn main() {
         }
     }
 
+    // A model given on the command line can be stepped through with the left
+    // and right arrow keys. The up and down arrow keys choose which of its
+    // submodels is shown, since an MPD's steps are usually in its submodels
+    // rather than in the main model.
+    let mut document: Vec<(String, Arc<parser::Geometry>)> = Vec::new();
+    if let Some(filename) = std::env::args().nth(1) {
+        match parser.load(&filename) {
+            Ok(geometry) => document.push((filename, geometry)),
+            Err(e) => eprintln!("ERROR: {}", e),
+        }
+        // The first submodel of an MPD is the main model, which is already
+        // loaded.
+        let names: Vec<String> = parser.submodels().iter().skip(1).map(|submodel| submodel.name.clone()).collect();
+        for name in names {
+            match parser.load_submodel(&name) {
+                Ok(geometry) => document.push((name, geometry)),
+                Err(e) => eprintln!("ERROR: {}", e),
+            }
+        }
+        print_diagnostics(&mut parser);
+    }
+    let mut document_model = None;
+    if let Some((_, geometry)) = document.first() {
+        state.step = geometry.steps.len().saturating_sub(1);
+        show_step(&mut graphics, &mut document_model, geometry, state.step);
+    }
+
+    let stats = parser.stats();
+    println!(
+        "load time: {} ms ({} files parsed in {} ms, {:.1}x speedup from parallel loading, {} parts from the disk cache)",
+        start.elapsed().as_millis(),
+        stats.files,
+        stats.elapsed.as_millis(),
+        stats.speedup(),
+        stats.cached,
+    );
+
     let mut new_brick_position = Vector3::new(2, 2, 2);
 
     event_loop.run(move |event, _, control_flow| {
@@ -192,38 +305,63 @@ fn main() {
                 WindowEvent::KeyboardInput { input, .. } => {
                     let pressed = input.state == ElementState::Pressed;
                     match input.virtual_keycode {
-                        Some(Key::T) => {
-                            if pressed {
-                                let mut model = load_ldraw_file(&mut graphics, &mut parser, "3005.dat", Some([1.0, 0.0, 0.0, 1.0]));
-                                model.position = new_brick_position;
-                                new_brick_position.y += 3;
-                                new_brick_position.z += 1;
-                                model.set_transform();
-                                models.push(model);
-                                state.active_model_idx = models.len() - 1;
-                            }
+                        Some(Key::LBracket) | Some(Key::RBracket) if pressed && !palette.is_empty() => {
+                            state.palette_idx = match input.virtual_keycode {
+                                Some(Key::LBracket) => (state.palette_idx + palette.len() - 1) % palette.len(),
+                                _ => (state.palette_idx + 1) % palette.len(),
+                            };
                         }
-                        Some(Key::R) => {
-                            if pressed {
-                                models[state.active_model_idx].rotation.y += 1;
-                                models[state.active_model_idx].rotation_offset.y = 90.0;
-                                models[state.active_model_idx].set_transform();
+                        Some(Key::T) if pressed => {
+                            let name = palette.get(state.palette_idx).map_or("3005.dat", |entry| &entry.name);
+                            let mut model = load_ldraw_file(&mut graphics, &mut parser, name, Some([1.0, 0.0, 0.0, 1.0]));
+                            model.position = new_brick_position;
+                            new_brick_position.y += 3;
+                            new_brick_position.z += 1;
+                            model.set_transform();
+                            models.push(model);
+                            state.active_model_idx = models.len() - 1;
+                        }
+                        Some(Key::Left) | Some(Key::Right) if pressed => {
+                            if let Some((_, geometry)) = document.get(state.submodel_idx) {
+                                let last_step = geometry.steps.len().saturating_sub(1);
+                                state.step = match input.virtual_keycode {
+                                    Some(Key::Left) => state.step.saturating_sub(1),
+                                    _ => (state.step + 1).min(last_step),
+                                };
+                                show_step(&mut graphics, &mut document_model, geometry, state.step);
                             }
                         }
+                        Some(Key::Up) | Some(Key::Down) if pressed && !document.is_empty() => {
+                            state.submodel_idx = match input.virtual_keycode {
+                                Some(Key::Up) => (state.submodel_idx + document.len() - 1) % document.len(),
+                                _ => (state.submodel_idx + 1) % document.len(),
+                            };
+                            let geometry = &document[state.submodel_idx].1;
+                            state.step = geometry.steps.len().saturating_sub(1);
+                            show_step(&mut graphics, &mut document_model, geometry, state.step);
+                        }
+                        Some(Key::E) if pressed => {
+                            graphics.show_edges = !graphics.show_edges;
+                        }
+                        Some(Key::R) if pressed => {
+                            models[state.active_model_idx].rotation.y += 1;
+                            models[state.active_model_idx].rotation_offset.y = 90.0;
+                            models[state.active_model_idx].set_transform();
+                        }
                         _ => {}
                     }
                 }
                 WindowEvent::MouseWheel { delta, .. } => {
                     match delta {
                         MouseScrollDelta::LineDelta(_x, y) => {
-                            state.camera.distance *= (10.0 - y as f32) / 10.0;
+                            state.camera.distance *= (10.0 - y) / 10.0;
                         }
                         MouseScrollDelta::PixelDelta(d) => {
                             state.camera.distance *= (100.0 - d.y as f32) / 100.0;
                         }
                     }
                 }
-                WindowEvent::CursorMoved { position, .. } => {
+                WindowEvent::CursorMoved { .. } => {
                     let dx = input.mouse_delta_x as f32;
                     let dy = input.mouse_delta_y as f32;
                     if input.mouse_middle_down {
@@ -247,11 +385,14 @@ fn main() {
                 graphics.clear(Color::new(0, 255, 255, 255));
                 let (view, proj) = get_global_transforms(&state);
                 graphics.start_3d();
-                graphics.draw_model(baseplate.vao, baseplate.vertex_buffer_length, mat_to_array(baseplate.transform), mat_to_array(view), mat_to_array(proj), view_position, light);
+                graphics.draw_model(&baseplate, mat_to_array(baseplate.transform), mat_to_array(view), mat_to_array(proj), view_position, light);
+                if let Some(model) = &document_model {
+                    graphics.draw_model(model, mat_to_array(model.transform), mat_to_array(view), mat_to_array(proj), view_position, light);
+                }
                 for model in &mut models {
-                    graphics.draw_model(model.vao, model.vertex_buffer_length,mat_to_array(model.transform), mat_to_array(view), mat_to_array(proj), view_position, light);
+                    graphics.draw_model(model, mat_to_array(model.transform), mat_to_array(view), mat_to_array(proj), view_position, light);
 
-                    if model.rotation_offset.y.abs() > std::f32::EPSILON {
+                    if model.rotation_offset.y.abs() > f32::EPSILON {
                         let direction = model.rotation_offset.y / model.rotation_offset.y.abs();
                         model.rotation_offset.y -= 15.0 * direction;
                         if model.rotation_offset.y < 15.0 {
@@ -261,9 +402,21 @@ fn main() {
                     }
                 }
                 graphics.draw_rect(Rect::new(0, 0, 100, 100), Color::new(0, 0, 0, 255));
-                graphics.draw_text(
+                let text_rect = graphics.draw_text(
                     &format!("Frame time: {}", start.elapsed().as_millis()),
                     20, 20, 256.0, Color::new(255, 0, 128, 255));
+                let mut y = text_rect.y + text_rect.height as i32;
+                if let Some(entry) = palette.get(state.palette_idx) {
+                    let rect = graphics.draw_text(
+                        &format!("[ ] {} {}", entry.number(), entry.title),
+                        20, y, 64.0, Color::new(255, 0, 128, 255));
+                    y = rect.y + rect.height as i32;
+                }
+                if let Some((name, geometry)) = document.get(state.submodel_idx) {
+                    graphics.draw_text(
+                        &format!("{} ({} / {}): Step {} / {}", name, state.submodel_idx + 1, document.len(), state.step + 1, geometry.steps.len()),
+                        20, y, 64.0, Color::new(255, 0, 128, 255));
+                }
                 graphics.swap();
             },
             _ => (),
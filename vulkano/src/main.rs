@@ -4,15 +4,25 @@ use std::f32::consts::PI;
 use winit::event::{DeviceEvent, ElementState, Event, WindowEvent};
 use winit::event_loop::{ControlFlow, EventLoop};
 
-mod parser;
-use parser::{norm, read_file, LdrawColor};
+use ldraw::parser::{norm, LdrawColor, Parser};
 
 mod renderer_vulkano;
 use renderer_vulkano::{VulkanoRenderer, Vertex};
 
 fn main() {
     let start = Instant::now();
-    let polygons = read_file("/home/paul/Downloads/ldraw/", "3001.dat", false);
+    let mut parser = Parser::new("/home/paul/Downloads/ldraw/").unwrap_or_else(|e| {
+        eprintln!("ERROR: couldn't open the library: {}", e);
+        std::process::exit(1);
+    });
+    let geometry = parser.load("3001.dat").unwrap_or_else(|e| {
+        eprintln!("ERROR: {}", e);
+        Default::default()
+    });
+    for diagnostic in parser.take_diagnostics() {
+        println!("WARNING: {}. Skipping...", diagnostic);
+    }
+    let polygons = &geometry.polygons;
     println!(
         "Loaded {} polygons in {} ms.",
         polygons.len(),
@@ -21,7 +31,7 @@ fn main() {
 
     let mut vertices = Vec::new();
 
-    for polygon in &polygons {
+    for polygon in polygons {
         let color = match polygon.color {
             LdrawColor::RGBA(r, g, b, a) => [r, g, b, a],
             _ => [0.0, 1.0, 0.0, 1.0],